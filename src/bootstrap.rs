@@ -0,0 +1,92 @@
+// Schedule of the Kademlia bootstrap, that is repeated to refresh the routing table.
+// While the routing table is empty, failed bootstraps are retried with an exponential backoff.
+// The addresses of the bootstrap peers are kept, so that the node can rejoin the network after it
+// lost all peers.
+
+use futures::{future::BoxFuture, prelude::*};
+use libp2p::{core::Multiaddr, kad::QueryId, PeerId};
+use std::{
+    task::{Context, Poll},
+    time::Duration,
+};
+
+// Delay of the first retry after a failed bootstrap, it doubles with every failure.
+const MIN_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+pub struct BootstrapSchedule {
+    peers: Vec<(PeerId, Multiaddr)>,
+    // interval of the routing table refresh, None disables the periodic refresh
+    interval: Option<Duration>,
+    backoff: Duration,
+    // None while a bootstrap is running or if nothing is scheduled
+    timer: Option<BoxFuture<'static, ()>>,
+    query: Option<QueryId>,
+}
+
+impl BootstrapSchedule {
+    pub fn new(interval: Option<Duration>) -> Self {
+        BootstrapSchedule {
+            peers: Vec::new(),
+            interval,
+            backoff: MIN_BACKOFF,
+            timer: None,
+            query: None,
+        }
+    }
+
+    pub fn add_peer(&mut self, peer: PeerId, addr: Multiaddr) {
+        self.peers.push((peer, addr));
+    }
+
+    pub fn peers(&self) -> &[(PeerId, Multiaddr)] {
+        &self.peers
+    }
+
+    // Id of the running bootstrap query.
+    pub fn query(&self) -> Option<QueryId> {
+        self.query
+    }
+
+    // Schedule a bootstrap after the delay, unless a bootstrap is running.
+    pub fn schedule(&mut self, delay: Duration) {
+        if self.query.is_none() {
+            self.timer = Some(async_std::task::sleep(delay).boxed());
+        }
+    }
+
+    // Ready once the scheduled bootstrap is due.
+    pub fn poll_due(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        match self.timer.as_mut().map(|timer| timer.poll_unpin(cx)) {
+            Some(Poll::Ready(())) => {
+                self.timer = None;
+                Poll::Ready(())
+            }
+            _ => Poll::Pending,
+        }
+    }
+
+    pub fn started(&mut self, query: QueryId) {
+        self.query = Some(query);
+        self.timer = None;
+    }
+
+    // The bootstrap filled the routing table, the next one refreshes it after the interval.
+    pub fn succeeded(&mut self) {
+        self.query = None;
+        self.backoff = MIN_BACKOFF;
+        if let Some(interval) = self.interval {
+            self.schedule(interval);
+        }
+    }
+
+    // The bootstrap could not be started or did not find any peers, it is retried after the
+    // backoff. Returns the delay of the retry.
+    pub fn failed(&mut self) -> Duration {
+        self.query = None;
+        let delay = self.backoff;
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+        self.schedule(delay);
+        delay
+    }
+}
@@ -0,0 +1,177 @@
+// Handlers for CommandRequest::Other messages.
+// Messages with a content-type header are dispatched by their content-type if a handler is
+// registered for it. Other commands are either DIDComm messages, that are dispatched by their
+// DIDComm message type, or plain text commands, that are dispatched by their first word
+// e.g. "ECHO hello" -> "ECHO".
+// Handlers can be registered at the P2PNetworkBehaviour at startup.
+
+use crate::command_protocol::{error_code, header, CommandResponse, Headers};
+use futures::future::BoxFuture;
+use libp2p::PeerId;
+use std::collections::HashMap;
+
+use identity_comm::did_comm::TrustPing;
+use identity_comm::types::TRUSTPING;
+use identity_comm::DIDComm_message;
+use identity_common::Timestamp;
+use identity_core::did::DID;
+
+// Response of a handler, either immediately available or computed asynchronously.
+pub enum HandlerResponse {
+    Ready(CommandResponse),
+    Pending(BoxFuture<'static, CommandResponse>),
+}
+
+impl From<CommandResponse> for HandlerResponse {
+    fn from(response: CommandResponse) -> Self {
+        HandlerResponse::Ready(response)
+    }
+}
+
+pub trait CommandHandler: Send {
+    // Handle the raw payload of a CommandRequest::Other that was sent by peer.
+    fn handle(&mut self, peer: &PeerId, headers: &Headers, payload: Vec<u8>) -> HandlerResponse;
+}
+
+impl<F> CommandHandler for F
+where
+    F: FnMut(&PeerId, &Headers, Vec<u8>) -> HandlerResponse + Send,
+{
+    fn handle(&mut self, peer: &PeerId, headers: &Headers, payload: Vec<u8>) -> HandlerResponse {
+        self(peer, headers, payload)
+    }
+}
+
+#[derive(Default)]
+pub struct CommandHandlerRegistry {
+    handlers: HashMap<String, Box<dyn CommandHandler>>,
+}
+
+impl CommandHandlerRegistry {
+    // Register a handler for a content-type, command name or DIDComm message type, replaces and returns an
+    // already registered handler for the same key.
+    pub fn register<H>(&mut self, key: impl Into<String>, handler: H) -> Option<Box<dyn CommandHandler>>
+    where
+        H: CommandHandler + 'static,
+    {
+        self.handlers.insert(key.into(), Box::new(handler))
+    }
+
+    pub fn unregister(&mut self, key: &str) -> Option<Box<dyn CommandHandler>> {
+        self.handlers.remove(key)
+    }
+
+    // Pass the payload to the handler that is registered for its key, or create an error response
+    // if the payload can not be parsed or no handler is registered.
+    pub fn dispatch(&mut self, peer: &PeerId, headers: &Headers, payload: Vec<u8>) -> HandlerResponse {
+        let content_type = headers
+            .get(header::CONTENT_TYPE)
+            .filter(|content_type| self.handlers.contains_key(*content_type));
+        let key = match content_type.cloned().or_else(|| command_key(&payload)) {
+            Some(key) => key,
+            None => return CommandResponse::error(error_code::BAD_REQUEST, "invalid UTF-8").into(),
+        };
+        match self.handlers.get_mut(&key) {
+            Some(handler) => handler.handle(peer, headers, payload),
+            None => CommandResponse::error(error_code::UNSUPPORTED, format!("unsupported command: {}", key)).into(),
+        }
+    }
+}
+
+// Key of the handler for a payload: the DIDComm message type or the first word of the command.
+fn command_key(payload: &[u8]) -> Option<String> {
+    let message = std::str::from_utf8(payload).ok()?;
+    if let Ok(did_comm_message) = serde_json::from_str::<DIDComm_message>(message) {
+        if let Some(message_type) = did_comm_message.type_() {
+            return Some(message_type.to_string());
+        }
+    }
+    Some(message.split_whitespace().next().unwrap_or_default().to_string())
+}
+
+// Answers a DIDComm TrustPing with a TrustPing that does not request a response.
+pub struct TrustPingHandler;
+
+impl CommandHandler for TrustPingHandler {
+    fn handle(&mut self, _peer: &PeerId, _headers: &Headers, payload: Vec<u8>) -> HandlerResponse {
+        let message = match serde_json::from_slice::<DIDComm_message>(&payload) {
+            Ok(message) => message,
+            Err(e) => {
+                return CommandResponse::Error {
+                    code: error_code::BAD_REQUEST,
+                    reason: "expected DIDComm message".into(),
+                    details: Some(e.to_string().into_bytes()),
+                }
+                .into()
+            }
+        };
+        // Send trust pong
+        let did = DID {
+            method_name: "iota".into(),
+            id_segments: vec!["pong".into()],
+            ..Default::default()
+        }
+        .init()
+        .unwrap();
+        let mut did_comm_message: DIDComm_message = DIDComm_message::new();
+        did_comm_message.set_id(Timestamp::now().to_rfc3339().to_string());
+        did_comm_message.set_from(did.to_string());
+        let sender = match message.from() {
+            Some(sender) => sender,
+            None => return CommandResponse::error(error_code::BAD_REQUEST, "missing sender").into(),
+        };
+        did_comm_message.set_to(sender);
+        did_comm_message.set_type(TRUSTPING);
+
+        let ping = TrustPing {
+            response_requested: false,
+        };
+        let value = serde_json::to_value(ping).unwrap();
+        let object = value.as_object().unwrap();
+        did_comm_message.set_body(object.clone());
+        let did_comm_message_string = serde_json::to_string(&did_comm_message).unwrap();
+
+        // sign message
+
+        // send signed response
+        CommandResponse::Other(did_comm_message_string.into_bytes()).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn echo(_: &PeerId, _: &Headers, payload: Vec<u8>) -> HandlerResponse {
+        CommandResponse::Other(payload).into()
+    }
+
+    fn dispatch(registry: &mut CommandHandlerRegistry, payload: &str) -> CommandResponse {
+        match registry.dispatch(&PeerId::random(), &Headers::new(), payload.as_bytes().to_vec()) {
+            HandlerResponse::Ready(response) => response,
+            HandlerResponse::Pending(_) => panic!("expected a ready response"),
+        }
+    }
+
+    #[test]
+    fn dispatches_by_first_word() {
+        let mut registry = CommandHandlerRegistry::default();
+        registry.register("ECHO", echo);
+        assert_eq!(
+            dispatch(&mut registry, "ECHO hello"),
+            CommandResponse::Other(b"ECHO hello".to_vec())
+        );
+    }
+
+    #[test]
+    fn unregistered_commands_are_unsupported() {
+        let mut registry = CommandHandlerRegistry::default();
+        registry.register("ECHO", echo);
+        assert!(registry.unregister("ECHO").is_some());
+        assert!(registry.unregister("ECHO").is_none());
+        assert!(matches!(
+            dispatch(&mut registry, "ECHO hello"),
+            CommandResponse::Error { code: error_code::UNSUPPORTED, .. }
+        ));
+    }
+}
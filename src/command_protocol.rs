@@ -1,118 +1,684 @@
 use crate::dht_proto as proto;
 use async_trait::async_trait;
-use futures::{prelude::*, AsyncRead, AsyncWrite};
+use async_std::future::timeout;
+use futures::{channel::mpsc, prelude::*, AsyncRead, AsyncWrite};
 use libp2p::{
     core::{
-        upgrade::{read_one, write_one},
+        upgrade::{read_one, write_one, write_varint, write_with_len_prefix, ReadOneError},
         ProtocolName,
     },
     request_response::RequestResponseCodec,
 };
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
 use prost::Message;
-use std::io;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Read, Write},
+    str::FromStr,
+    time::Duration,
+};
+
+// Size of a single chunk on the wire, payloads are split into chunks of at most this size.
+const CHUNK_SIZE: usize = 1024;
+// Default upper bound for the total size of a (re-assembled) request or response.
+pub const DEFAULT_MAX_PAYLOAD_SIZE: usize = 16 * 1024 * 1024;
+// Default time to read a whole request or response.
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+// Default size from which on encoded messages are compressed.
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = CHUNK_SIZE;
+
+// Versions of the command protocol, that are negotiated during the substream upgrade.
+// V1: The message is sent as a single length-prefixed frame, it only supports Ping and Other
+// messages, errors are sent as Other.
+// V2: The message is sent in chunks and supports the full message schema, messages above the
+// compression threshold are sent deflate compressed.
+// Json and Cbor: Alternative encodings of the V2 schema for clients without protobuf support,
+// the serialized Envelope is sent as a single length-prefixed frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandProtocol {
+    V1,
+    V2,
+    Json,
+    Cbor,
+}
+
+impl CommandProtocol {
+    // All supported versions and encodings, ordered by preference.
+    pub fn supported() -> impl Iterator<Item = CommandProtocol> {
+        vec![
+            CommandProtocol::V2,
+            CommandProtocol::V1,
+            CommandProtocol::Json,
+            CommandProtocol::Cbor,
+        ]
+        .into_iter()
+    }
+}
+
+impl FromStr for CommandProtocol {
+    type Err = String;
 
+    // Parse the encoding name that is used on the command line.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "protobuf" | "v2" => Ok(CommandProtocol::V2),
+            "v1" => Ok(CommandProtocol::V1),
+            "json" => Ok(CommandProtocol::Json),
+            "cbor" => Ok(CommandProtocol::Cbor),
+            _ => Err(format!("unknown protocol: {}", s)),
+        }
+    }
+}
+
+// Limits of a CommandCodec, sizes refer to the (decompressed) encoded message.
 #[derive(Debug, Clone)]
-pub struct CommandProtocol();
-#[derive(Clone)]
-pub struct CommandCodec();
+pub struct CodecConfig {
+    pub max_request_size: usize,
+    pub max_response_size: usize,
+    // Time to read a whole message, None disables the timeout.
+    pub read_timeout: Option<Duration>,
+    // Messages larger than this are compressed if the protocol supports it, None disables
+    // compression.
+    pub compression_threshold: Option<usize>,
+}
+
+impl Default for CodecConfig {
+    fn default() -> Self {
+        CodecConfig {
+            max_request_size: DEFAULT_MAX_PAYLOAD_SIZE,
+            max_response_size: DEFAULT_MAX_PAYLOAD_SIZE,
+            read_timeout: Some(DEFAULT_READ_TIMEOUT),
+            compression_threshold: Some(DEFAULT_COMPRESSION_THRESHOLD),
+        }
+    }
+}
+
+// Number of rejected messages that are buffered until the behaviour reports them, further
+// rejections are dropped.
+const REJECTED_BUFFER_SIZE: usize = 64;
+
+#[derive(Clone, Default)]
+pub struct CommandCodec {
+    config: CodecConfig,
+    rejected: Option<mpsc::Sender<RejectedMessage>>,
+}
+
+impl CommandCodec {
+    pub fn new(config: CodecConfig) -> Self {
+        CommandCodec { config, rejected: None }
+    }
+
+    // Receiver of the messages that this codec and its clones reject, replaces a previous receiver.
+    pub fn rejected_messages(&mut self) -> mpsc::Receiver<RejectedMessage> {
+        let (sender, receiver) = mpsc::channel(REJECTED_BUFFER_SIZE);
+        self.rejected = Some(sender);
+        receiver
+    }
+
+    // Report oversized messages and timeouts, that are otherwise not reported by the
+    // RequestResponse behaviour.
+    fn report_read_error(&mut self, kind: &'static str, e: io::Error) -> io::Error {
+        let is_too_large = e.get_ref().map_or(false, |e| e.is::<MessageTooLarge>());
+        if is_too_large || e.kind() == io::ErrorKind::TimedOut {
+            if let Some(rejected) = self.rejected.as_mut() {
+                let _ = rejected.try_send(RejectedMessage {
+                    kind,
+                    reason: e.to_string(),
+                });
+            }
+        }
+        e
+    }
+}
+
+// A request or response that the codec rejected because it was too large or not read in time.
+#[derive(Debug)]
+pub struct RejectedMessage {
+    // "request" or "response"
+    pub kind: &'static str,
+    pub reason: String,
+}
+
+// Error for messages that exceed the size limit of the codec, it is wrapped in an io::Error with
+// io::ErrorKind::InvalidData.
+#[derive(Debug)]
+pub struct MessageTooLarge {
+    pub size: usize,
+    pub max: usize,
+}
+
+impl fmt::Display for MessageTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "message of at least {} bytes exceeds maximum size of {} bytes", self.size, self.max)
+    }
+}
+
+impl std::error::Error for MessageTooLarge {}
+
+impl From<MessageTooLarge> for io::Error {
+    fn from(e: MessageTooLarge) -> Self {
+        invalid_data(e)
+    }
+}
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum CommandRequest {
     Ping,
-    Other(Vec<u8>),
+    Other(#[serde(with = "base64_bytes")] Vec<u8>),
+    // Key-value commands for the store of the remote peer
+    Get { key: String },
+    Put {
+        key: String,
+        #[serde(with = "base64_bytes")]
+        value: Vec<u8>,
+    },
+    Delete { key: String },
+    List { prefix: String },
+    // Ask the remote peer to forward the request to the target peer and to reply with its response
+    Forward {
+        target: String,
+        request: Box<Envelope<CommandRequest>>,
+    },
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum CommandResponse {
     Pong,
-    Other(Vec<u8>),
+    Other(#[serde(with = "base64_bytes")] Vec<u8>),
+    Error {
+        code: u32,
+        reason: String,
+        #[serde(default, with = "optional_bytes")]
+        details: Option<Vec<u8>>,
+    },
+    // Responses to the key-value commands
+    Value(#[serde(with = "base64_bytes")] Vec<u8>),
+    Stored,
+    Deleted,
+    Keys(Vec<String>),
+}
+
+// Byte fields are base64 strings in JSON and byte strings in CBOR, instead of arrays of numbers.
+pub(crate) mod base64_bytes {
+    use serde::{
+        de::{self, SeqAccess, Visitor},
+        Deserializer, Serializer,
+    };
+    use std::fmt;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&base64::encode(bytes))
+        } else {
+            serializer.serialize_bytes(bytes)
+        }
+    }
+
+    // JSON and CBOR are self-describing, so arrays of numbers are accepted as well
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        deserializer.deserialize_any(BytesVisitor)
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a base64 string or a byte string")
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+            base64::decode(v).map_err(E::custom)
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+            Ok(v.to_vec())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+            Ok(v)
+        }
+
+        // arrays of numbers, as the byte fields were encoded before
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(bytes)
+        }
+    }
+}
+
+mod optional_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+        match bytes {
+            Some(bytes) => serializer.serialize_some(&Bytes(bytes)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error> {
+        Ok(Option::<ByteBuf>::deserialize(deserializer)?.map(|bytes| bytes.0))
+    }
+
+    struct Bytes<'a>(&'a [u8]);
+
+    impl serde::Serialize for Bytes<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            super::base64_bytes::serialize(self.0, serializer)
+        }
+    }
+
+    struct ByteBuf(Vec<u8>);
+
+    impl<'de> Deserialize<'de> for ByteBuf {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            super::base64_bytes::deserialize(deserializer).map(ByteBuf)
+        }
+    }
+}
+
+// Metadata of a message, see the header module for well-known names.
+pub type Headers = HashMap<String, String>;
+
+// Names of the well-known headers
+pub mod header {
+    // Milliseconds since the unix epoch at which the message was created
+    pub const TIMESTAMP: &str = "timestamp";
+    // Id to correlate messages across multiple hops, responses copy it from the request
+    pub const TRACE_ID: &str = "trace-id";
+    // Type of the payload e.g. "application/json", handlers may be registered for it
+    pub const CONTENT_TYPE: &str = "content-type";
+    // Id of the application that sent the message
+    pub const SENDER_APP_ID: &str = "sender-app-id";
+    // Milliseconds after which a rate limited request may be sent again
+    pub const RETRY_AFTER: &str = "retry-after";
+}
+
+// A request or response together with its headers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    #[serde(default)]
+    pub headers: Headers,
+    pub body: T,
+}
+
+impl<T> Envelope<T> {
+    pub fn new(body: T) -> Self {
+        Envelope {
+            headers: Headers::new(),
+            body,
+        }
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.insert(name.into(), value.into());
+        self
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+}
+
+impl CommandRequest {
+    // Name of the command type, e.g. for logging.
+    pub fn name(&self) -> &'static str {
+        match self {
+            CommandRequest::Ping => "PING",
+            CommandRequest::Other(_) => "OTHER",
+            CommandRequest::Get { .. } => "GET",
+            CommandRequest::Put { .. } => "PUT",
+            CommandRequest::Delete { .. } => "DELETE",
+            CommandRequest::List { .. } => "LIST",
+            CommandRequest::Forward { .. } => "FORWARD",
+        }
+    }
+
+    // Whether executing the request twice has the same effect as executing it once. Requests
+    // that are not idempotent may have been executed by the peer although the response timed out.
+    pub fn is_idempotent(&self) -> bool {
+        match self {
+            CommandRequest::Ping | CommandRequest::Get { .. } | CommandRequest::List { .. } => true,
+            CommandRequest::Other(_)
+            | CommandRequest::Put { .. }
+            | CommandRequest::Delete { .. }
+            | CommandRequest::Forward { .. } => false,
+        }
+    }
+}
+
+impl CommandResponse {
+    // Create an error response without details
+    pub fn error(code: u32, reason: impl Into<String>) -> Self {
+        CommandResponse::Error {
+            code,
+            reason: reason.into(),
+            details: None,
+        }
+    }
+}
+
+// Codes used in CommandResponse::Error
+pub mod error_code {
+    // The request could not be parsed.
+    pub const BAD_REQUEST: u32 = 400;
+    // The sender is banned because of misbehaviour.
+    pub const FORBIDDEN: u32 = 403;
+    // The requested key does not exist.
+    pub const NOT_FOUND: u32 = 404;
+    // The value exceeds the size limit of the key-value store.
+    pub const PAYLOAD_TOO_LARGE: u32 = 413;
+    // The sender exceeded the rate limit, the retry-after header tells when to retry.
+    pub const TOO_MANY_REQUESTS: u32 = 429;
+    // The request was parsed, but its type is not supported by this node.
+    pub const UNSUPPORTED: u32 = 501;
+    // The forwarding peer could not reach the target of the request.
+    pub const BAD_GATEWAY: u32 = 502;
+    // The key-value store reached its limit of keys or bytes.
+    pub const INSUFFICIENT_STORAGE: u32 = 507;
 }
 
 impl ProtocolName for CommandProtocol {
     fn protocol_name(&self) -> &[u8] {
-        b"/custom-retrieve/1.0.0"
+        match self {
+            CommandProtocol::V1 => b"/custom-retrieve/1.0.0",
+            CommandProtocol::V2 => b"/custom-retrieve/2.0.0",
+            CommandProtocol::Json => b"/custom-retrieve-json/2.0.0",
+            CommandProtocol::Cbor => b"/custom-retrieve-cbor/2.0.0",
+        }
     }
 }
 
 #[async_trait]
 impl RequestResponseCodec for CommandCodec {
     type Protocol = CommandProtocol;
-    type Request = CommandRequest;
-    type Response = CommandResponse;
+    type Request = Envelope<CommandRequest>;
+    type Response = Envelope<CommandResponse>;
 
     async fn read_request<T>(
         &mut self,
-        _: &CommandProtocol,
+        protocol: &CommandProtocol,
         io: &mut T,
     ) -> io::Result<Self::Request>
     where
         T: AsyncRead + Unpin + Send,
     {
-        read_one(io, 1024)
-            .map(|req| match req {
-                Ok(bytes) => {
-                    let request = proto::Message::decode(io::Cursor::new(bytes))?;
-                    proto_msg_to_req(request)
-                }
-                Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
-            })
-            .await
+        let max_size = self.config.max_request_size;
+        let bytes = self.read_frame(protocol, io, max_size).await;
+        let bytes = bytes.map_err(|e| self.report_read_error("request", e))?;
+        match protocol {
+            CommandProtocol::V1 | CommandProtocol::V2 => {
+                let mut request = decode_msg(protocol, bytes, max_size).map_err(|e| self.report_read_error("request", e))?;
+                let headers = std::mem::take(&mut request.headers);
+                let body = proto_msg_to_req(request)?;
+                Ok(Envelope { headers, body })
+            }
+            CommandProtocol::Json | CommandProtocol::Cbor => decode_serde(protocol, &bytes),
+        }
     }
 
     async fn read_response<T>(
         &mut self,
-        _: &CommandProtocol,
+        protocol: &CommandProtocol,
         io: &mut T,
     ) -> io::Result<Self::Response>
     where
         T: AsyncRead + Unpin + Send,
     {
-        read_one(io, 1024)
-            .map(|res| match res {
-                Ok(bytes) => {
-                    let response = proto::Message::decode(io::Cursor::new(bytes))?;
-                    proto_msg_to_res(response)
-                }
-                Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
-            })
-            .await
+        let max_size = self.config.max_response_size;
+        let bytes = self.read_frame(protocol, io, max_size).await;
+        let bytes = bytes.map_err(|e| self.report_read_error("response", e))?;
+        match protocol {
+            CommandProtocol::V1 | CommandProtocol::V2 => {
+                let mut response = decode_msg(protocol, bytes, max_size).map_err(|e| self.report_read_error("response", e))?;
+                let headers = std::mem::take(&mut response.headers);
+                let body = proto_msg_to_res(response)?;
+                Ok(Envelope { headers, body })
+            }
+            CommandProtocol::Json | CommandProtocol::Cbor => decode_serde(protocol, &bytes),
+        }
     }
 
     async fn write_request<T>(
         &mut self,
-        _: &CommandProtocol,
+        protocol: &CommandProtocol,
         io: &mut T,
-        req: CommandRequest,
+        req: Envelope<CommandRequest>,
     ) -> io::Result<()>
     where
         T: AsyncWrite + Unpin + Send,
     {
-        let proto_struct = req_to_proto_msg(req);
-        let mut buf = Vec::with_capacity(proto_struct.encoded_len());
-        proto_struct
-            .encode(&mut buf)
-            .expect("Vec<u8> provides capacity as needed");
-        write_one(io, buf).await
+        let max_size = self.config.max_request_size;
+        let bytes = match protocol {
+            CommandProtocol::V1 | CommandProtocol::V2 => {
+                if *protocol == CommandProtocol::V1 {
+                    check_req_v1(&req.body)?;
+                }
+                let mut proto_struct = req_to_proto_msg(req.body);
+                proto_struct.headers = req.headers;
+                self.encode_msg(protocol, proto_struct, max_size)?
+            }
+            CommandProtocol::Json | CommandProtocol::Cbor => encode_serde(protocol, &req, max_size)?,
+        };
+        write_frame(protocol, io, bytes).await
     }
 
     async fn write_response<T>(
         &mut self,
-        _: &CommandProtocol,
+        protocol: &CommandProtocol,
         io: &mut T,
-        res: CommandResponse,
+        res: Envelope<CommandResponse>,
     ) -> io::Result<()>
     where
         T: AsyncWrite + Unpin + Send,
     {
-        let proto_struct = res_to_proto_msg(res);
-        let mut buf = Vec::with_capacity(proto_struct.encoded_len());
-        proto_struct
-            .encode(&mut buf)
-            .expect("Vec<u8> provides capacity as needed");
-        write_one(io, buf).await
+        let max_size = self.config.max_response_size;
+        let bytes = match protocol {
+            CommandProtocol::V1 | CommandProtocol::V2 => {
+                let body = match protocol {
+                    CommandProtocol::V1 => downgrade_res_v1(res.body),
+                    _ => res.body,
+                };
+                let mut proto_struct = res_to_proto_msg(body);
+                proto_struct.headers = res.headers;
+                self.encode_msg(protocol, proto_struct, max_size)?
+            }
+            CommandProtocol::Json | CommandProtocol::Cbor => encode_serde(protocol, &res, max_size)?,
+        };
+        write_frame(protocol, io, bytes).await
     }
 }
 
+impl CommandCodec {
+    // Read a frame of at most max_size bytes within the read timeout.
+    async fn read_frame<T>(&self, protocol: &CommandProtocol, io: &mut T, max_size: usize) -> io::Result<Vec<u8>>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let read = async {
+            match protocol {
+                CommandProtocol::V2 => read_chunked(io, max_size).await,
+                _ => read_one(io, max_size).await.map_err(|e| match e {
+                    ReadOneError::TooLarge { requested, max } => MessageTooLarge { size: requested, max }.into(),
+                    e => invalid_data(e),
+                }),
+            }
+        };
+        match self.config.read_timeout {
+            Some(read_timeout) => timeout(read_timeout, read)
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::TimedOut, e))?,
+            None => read.await,
+        }
+    }
+
+    // Encode a protobuf message, on V2 messages above the compression threshold are compressed.
+    fn encode_msg(&self, protocol: &CommandProtocol, msg: proto::Message, max_size: usize) -> io::Result<Vec<u8>> {
+        check_size(msg.encoded_len(), max_size)?;
+        let mut buf = encode_msg(&msg);
+        if *protocol == CommandProtocol::V2 {
+            if let Some(threshold) = self.config.compression_threshold {
+                if buf.len() > threshold {
+                    let compressed = encode_msg(&compress_msg(&buf)?);
+                    if compressed.len() < buf.len() {
+                        buf = compressed;
+                    }
+                }
+            }
+        }
+        Ok(buf)
+    }
+}
+
+// V2 sends the frame in chunks, all other protocols send it as a single length-prefixed frame.
+async fn write_frame<T>(protocol: &CommandProtocol, io: &mut T, bytes: Vec<u8>) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+{
+    match protocol {
+        CommandProtocol::V2 => write_chunked(io, bytes).await,
+        _ => write_one(io, bytes).await,
+    }
+}
+
+fn decode_msg(protocol: &CommandProtocol, bytes: Vec<u8>, max_size: usize) -> io::Result<proto::Message> {
+    let msg = proto::Message::decode(io::Cursor::new(bytes))?;
+    match protocol {
+        CommandProtocol::V2 => decompress_msg(msg, max_size),
+        _ => Ok(msg),
+    }
+}
+
+fn decode_serde<M: DeserializeOwned>(protocol: &CommandProtocol, bytes: &[u8]) -> io::Result<M> {
+    match protocol {
+        CommandProtocol::Cbor => serde_cbor::from_slice(bytes).map_err(invalid_data),
+        _ => serde_json::from_slice(bytes).map_err(invalid_data),
+    }
+}
+
+fn encode_serde<M: Serialize>(protocol: &CommandProtocol, msg: &M, max_size: usize) -> io::Result<Vec<u8>> {
+    let bytes = match protocol {
+        CommandProtocol::Cbor => serde_cbor::to_vec(msg).map_err(invalid_data)?,
+        _ => serde_json::to_vec(msg).map_err(invalid_data)?,
+    };
+    check_size(bytes.len(), max_size)?;
+    Ok(bytes)
+}
+
+fn check_size(size: usize, max_size: usize) -> io::Result<()> {
+    if size > max_size {
+        return Err(MessageTooLarge { size, max: max_size }.into());
+    }
+    Ok(())
+}
+
+fn encode_msg(msg: &proto::Message) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(msg.encoded_len());
+    msg.encode(&mut buf)
+        .expect("Vec<u8> provides capacity as needed");
+    buf
+}
+
+// Wrap the encoded message into a message that only contains the compressed bytes.
+fn compress_msg(encoded: &[u8]) -> io::Result<proto::Message> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(encoded)?;
+    Ok(proto::Message {
+        compression: proto::message::Compression::Deflate as i32,
+        compressed: encoder.finish()?,
+        ..proto::Message::default()
+    })
+}
+
+// Unwrap a compressed message, the decompressed size is limited to max_size.
+fn decompress_msg(msg: proto::Message, max_size: usize) -> io::Result<proto::Message> {
+    let compression = proto::message::Compression::from_i32(msg.compression)
+        .ok_or_else(|| invalid_data(format!("unknown compression: {}", msg.compression)))?;
+    match compression {
+        proto::message::Compression::None => Ok(msg),
+        proto::message::Compression::Deflate => {
+            let mut buf = Vec::new();
+            DeflateDecoder::new(&msg.compressed[..])
+                .take((max_size as u64).saturating_add(1))
+                .read_to_end(&mut buf)?;
+            if buf.len() > max_size {
+                return Err(MessageTooLarge {
+                    size: buf.len(),
+                    max: max_size,
+                }
+                .into());
+            }
+            let inner = proto::Message::decode(io::Cursor::new(buf))?;
+            if inner.compression != proto::message::Compression::None as i32 {
+                return Err(invalid_data("nested compression"));
+            }
+            Ok(inner)
+        }
+    }
+}
+
+// V1 has no error message type, errors are sent as Other with a textual description.
+fn downgrade_res_v1(res: CommandResponse) -> CommandResponse {
+    match res {
+        CommandResponse::Error { code, reason, .. } => {
+            CommandResponse::Other(format!("Error {}: {}", code, reason).into_bytes())
+        }
+        res => res,
+    }
+}
+
+// V1 only knows Ping and Other requests.
+fn check_req_v1(req: &CommandRequest) -> io::Result<()> {
+    match req {
+        CommandRequest::Ping | CommandRequest::Other(_) => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "request is not supported by protocol version 1.0.0",
+        )),
+    }
+}
+
+// Read length-prefixed chunks until the terminating empty chunk and re-assemble the payload.
+async fn read_chunked<T>(io: &mut T, max_size: usize) -> io::Result<Vec<u8>>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let mut payload = Vec::new();
+    loop {
+        let chunk = read_one(io, CHUNK_SIZE).await.map_err(invalid_data)?;
+        if chunk.is_empty() {
+            return Ok(payload);
+        }
+        if payload.len() + chunk.len() > max_size {
+            return Err(MessageTooLarge {
+                size: payload.len() + chunk.len(),
+                max: max_size,
+            }
+            .into());
+        }
+        payload.extend_from_slice(&chunk);
+    }
+}
+
+// Split the payload into chunks of at most CHUNK_SIZE, terminate with an empty chunk and close the stream.
+async fn write_chunked<T>(io: &mut T, payload: Vec<u8>) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+{
+    for chunk in payload.chunks(CHUNK_SIZE) {
+        write_with_len_prefix(io, chunk).await?;
+    }
+    write_varint(io, 0).await?;
+    io.close().await
+}
+
 fn proto_msg_to_req(msg: proto::Message) -> Result<CommandRequest, io::Error> {
     let msg_type = proto::message::MessageType::from_i32(msg.r#type)
         .ok_or_else(|| invalid_data(format!("unknown message type: {}", msg.r#type)))?;
@@ -122,6 +688,28 @@ fn proto_msg_to_req(msg: proto::Message) -> Result<CommandRequest, io::Error> {
             let cmd = msg.cmd;
             Ok(CommandRequest::Other(cmd))
         }
+        proto::message::MessageType::Error => Err(invalid_data("unexpected error message in request")),
+        proto::message::MessageType::Get => Ok(CommandRequest::Get { key: msg.key }),
+        proto::message::MessageType::Put => Ok(CommandRequest::Put {
+            key: msg.key,
+            value: msg.value,
+        }),
+        proto::message::MessageType::Delete => Ok(CommandRequest::Delete { key: msg.key }),
+        proto::message::MessageType::List => Ok(CommandRequest::List { prefix: msg.key }),
+        proto::message::MessageType::Forward => {
+            let mut forwarded = proto::Message::decode(io::Cursor::new(msg.forwarded))?;
+            // checked before decoding the inner request, so that deeply nested forwards can not
+            // overflow the stack
+            if forwarded.r#type == proto::message::MessageType::Forward as i32 {
+                return Err(invalid_data("nested forward requests are not supported"));
+            }
+            let headers = std::mem::take(&mut forwarded.headers);
+            let body = proto_msg_to_req(forwarded)?;
+            Ok(CommandRequest::Forward {
+                target: msg.target,
+                request: Box::new(Envelope { headers, body }),
+            })
+        }
     }
 }
 
@@ -134,6 +722,26 @@ fn proto_msg_to_res(msg: proto::Message) -> Result<CommandResponse, io::Error> {
             let result = msg.result;
             Ok(CommandResponse::Other(result))
         }
+        proto::message::MessageType::Error => {
+            let error = msg
+                .error
+                .ok_or_else(|| invalid_data("missing error in error message"))?;
+            let details = if error.details.is_empty() {
+                None
+            } else {
+                Some(error.details)
+            };
+            Ok(CommandResponse::Error {
+                code: error.code,
+                reason: error.reason,
+                details,
+            })
+        }
+        proto::message::MessageType::Get => Ok(CommandResponse::Value(msg.value)),
+        proto::message::MessageType::Put => Ok(CommandResponse::Stored),
+        proto::message::MessageType::Delete => Ok(CommandResponse::Deleted),
+        proto::message::MessageType::List => Ok(CommandResponse::Keys(msg.keys)),
+        proto::message::MessageType::Forward => Err(invalid_data("unexpected forward message in response")),
     }
 }
 
@@ -148,6 +756,37 @@ fn req_to_proto_msg(req: CommandRequest) -> proto::Message {
             cmd,
             ..proto::Message::default()
         },
+        CommandRequest::Get { key } => proto::Message {
+            r#type: proto::message::MessageType::Get as i32,
+            key,
+            ..proto::Message::default()
+        },
+        CommandRequest::Put { key, value } => proto::Message {
+            r#type: proto::message::MessageType::Put as i32,
+            key,
+            value,
+            ..proto::Message::default()
+        },
+        CommandRequest::Delete { key } => proto::Message {
+            r#type: proto::message::MessageType::Delete as i32,
+            key,
+            ..proto::Message::default()
+        },
+        CommandRequest::List { prefix } => proto::Message {
+            r#type: proto::message::MessageType::List as i32,
+            key: prefix,
+            ..proto::Message::default()
+        },
+        CommandRequest::Forward { target, request } => {
+            let mut forwarded = req_to_proto_msg(request.body);
+            forwarded.headers = request.headers;
+            proto::Message {
+                r#type: proto::message::MessageType::Forward as i32,
+                target,
+                forwarded: encode_msg(&forwarded),
+                ..proto::Message::default()
+            }
+        }
     }
 }
 
@@ -162,6 +801,37 @@ fn res_to_proto_msg(res: CommandResponse) -> proto::Message {
             result,
             ..proto::Message::default()
         },
+        CommandResponse::Error {
+            code,
+            reason,
+            details,
+        } => proto::Message {
+            r#type: proto::message::MessageType::Error as i32,
+            error: Some(proto::message::Error {
+                code,
+                reason,
+                details: details.unwrap_or_default(),
+            }),
+            ..proto::Message::default()
+        },
+        CommandResponse::Value(value) => proto::Message {
+            r#type: proto::message::MessageType::Get as i32,
+            value,
+            ..proto::Message::default()
+        },
+        CommandResponse::Stored => proto::Message {
+            r#type: proto::message::MessageType::Put as i32,
+            ..proto::Message::default()
+        },
+        CommandResponse::Deleted => proto::Message {
+            r#type: proto::message::MessageType::Delete as i32,
+            ..proto::Message::default()
+        },
+        CommandResponse::Keys(keys) => proto::Message {
+            r#type: proto::message::MessageType::List as i32,
+            keys,
+            ..proto::Message::default()
+        },
     }
 }
 
@@ -172,3 +842,228 @@ where
 {
     io::Error::new(io::ErrorKind::InvalidData, e)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, io::Cursor};
+
+    fn write_chunked_to_vec(payload: Vec<u8>) -> Vec<u8> {
+        let mut io = Cursor::new(Vec::new());
+        block_on(write_chunked(&mut io, payload)).unwrap();
+        io.into_inner()
+    }
+
+    fn is_too_large(e: &io::Error) -> bool {
+        e.kind() == io::ErrorKind::InvalidData
+            && e.get_ref().and_then(|e| e.downcast_ref::<MessageTooLarge>()).is_some()
+    }
+
+    #[test]
+    fn chunked_round_trip() {
+        for size in &[0, 1, CHUNK_SIZE, CHUNK_SIZE + 1, 3 * CHUNK_SIZE + 17] {
+            let payload: Vec<u8> = (0..*size).map(|i| i as u8).collect();
+            let mut io = Cursor::new(write_chunked_to_vec(payload.clone()));
+            assert_eq!(block_on(read_chunked(&mut io, *size)).unwrap(), payload);
+        }
+    }
+
+    #[test]
+    fn chunked_rejects_payload_above_max_size() {
+        let bytes = write_chunked_to_vec(vec![0; 2 * CHUNK_SIZE + 1]);
+        let e = block_on(read_chunked(&mut Cursor::new(bytes), 2 * CHUNK_SIZE)).unwrap_err();
+        assert!(is_too_large(&e));
+    }
+
+    #[test]
+    fn chunked_rejects_oversized_chunk() {
+        let mut io = Cursor::new(Vec::new());
+        block_on(write_with_len_prefix(&mut io, vec![0; CHUNK_SIZE + 1])).unwrap();
+        let bytes = io.into_inner();
+        let e = block_on(read_chunked(&mut Cursor::new(bytes), 4 * CHUNK_SIZE)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn chunked_rejects_truncated_stream() {
+        let mut bytes = write_chunked_to_vec(vec![1; CHUNK_SIZE]);
+        // drop the terminating empty chunk
+        bytes.pop();
+        assert!(block_on(read_chunked(&mut Cursor::new(bytes), 4 * CHUNK_SIZE)).is_err());
+    }
+
+    fn large_msg() -> proto::Message {
+        proto::Message {
+            r#type: proto::message::MessageType::Put as i32,
+            key: "key".to_string(),
+            value: vec![7; 64 * 1024],
+            ..proto::Message::default()
+        }
+    }
+
+    #[test]
+    fn deflate_round_trip() {
+        let msg = large_msg();
+        let compressed = compress_msg(&encode_msg(&msg)).unwrap();
+        assert!(compressed.compressed.len() < msg.value.len());
+        assert_eq!(decompress_msg(compressed, msg.encoded_len()).unwrap(), msg);
+    }
+
+    #[test]
+    fn deflate_rejects_message_above_max_size() {
+        let msg = large_msg();
+        let compressed = compress_msg(&encode_msg(&msg)).unwrap();
+        let e = decompress_msg(compressed, msg.encoded_len() - 1).unwrap_err();
+        assert!(is_too_large(&e));
+    }
+
+    #[test]
+    fn deflate_rejects_nested_compression() {
+        let inner = compress_msg(&encode_msg(&large_msg())).unwrap();
+        let outer = compress_msg(&encode_msg(&inner)).unwrap();
+        let e = decompress_msg(outer, DEFAULT_MAX_PAYLOAD_SIZE).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn codec_compresses_large_v2_messages_only() {
+        let codec = CommandCodec::default();
+        let msg = large_msg();
+        let v2 = codec.encode_msg(&CommandProtocol::V2, msg.clone(), DEFAULT_MAX_PAYLOAD_SIZE).unwrap();
+        assert!(v2.len() < msg.encoded_len());
+        assert_eq!(decode_msg(&CommandProtocol::V2, v2, DEFAULT_MAX_PAYLOAD_SIZE).unwrap(), msg);
+        let v1 = codec.encode_msg(&CommandProtocol::V1, msg.clone(), DEFAULT_MAX_PAYLOAD_SIZE).unwrap();
+        assert_eq!(v1.len(), msg.encoded_len());
+    }
+
+    fn forward_msg(forwarded: &proto::Message) -> proto::Message {
+        proto::Message {
+            r#type: proto::message::MessageType::Forward as i32,
+            target: "target".to_string(),
+            forwarded: encode_msg(forwarded),
+            ..proto::Message::default()
+        }
+    }
+
+    #[test]
+    fn decodes_forwarded_request() {
+        let ping = req_to_proto_msg(CommandRequest::Ping);
+        match proto_msg_to_req(forward_msg(&ping)).unwrap() {
+            CommandRequest::Forward { request, .. } => assert_eq!(request.body, CommandRequest::Ping),
+            req => panic!("unexpected request: {:?}", req),
+        }
+    }
+
+    #[test]
+    fn rejects_nested_forward_requests() {
+        let ping = req_to_proto_msg(CommandRequest::Ping);
+        let nested = forward_msg(&forward_msg(&ping));
+        let e = proto_msg_to_req(nested).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_forward_message_in_response() {
+        let ping = req_to_proto_msg(CommandRequest::Ping);
+        let e = proto_msg_to_res(forward_msg(&ping)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    fn requests() -> Vec<Envelope<CommandRequest>> {
+        let put = CommandRequest::Put {
+            key: "key".to_string(),
+            value: vec![0, 1, 2, 255],
+        };
+        vec![
+            Envelope::new(CommandRequest::Ping).with_header(header::TRACE_ID, "0123"),
+            Envelope::new(CommandRequest::Other(b"ECHO hello".to_vec())),
+            Envelope::new(put.clone()),
+            Envelope::new(CommandRequest::Forward {
+                target: "target".to_string(),
+                request: Box::new(Envelope::new(put).with_header(header::CONTENT_TYPE, "text/plain")),
+            }),
+        ]
+    }
+
+    fn responses() -> Vec<Envelope<CommandResponse>> {
+        vec![
+            Envelope::new(CommandResponse::Pong).with_header(header::TRACE_ID, "0123"),
+            Envelope::new(CommandResponse::Value(vec![0, 1, 2, 255])),
+            Envelope::new(CommandResponse::Error {
+                code: error_code::BAD_REQUEST,
+                reason: "invalid".to_string(),
+                details: Some(vec![42]),
+            }),
+            Envelope::new(CommandResponse::error(error_code::UNSUPPORTED, "unsupported")),
+            Envelope::new(CommandResponse::Keys(vec!["a".to_string(), "b".to_string()])),
+        ]
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        for protocol in &[CommandProtocol::Json, CommandProtocol::Cbor] {
+            for request in requests() {
+                let bytes = encode_serde(protocol, &request, DEFAULT_MAX_PAYLOAD_SIZE).unwrap();
+                assert_eq!(decode_serde::<Envelope<CommandRequest>>(protocol, &bytes).unwrap(), request);
+            }
+            for response in responses() {
+                let bytes = encode_serde(protocol, &response, DEFAULT_MAX_PAYLOAD_SIZE).unwrap();
+                assert_eq!(decode_serde::<Envelope<CommandResponse>>(protocol, &bytes).unwrap(), response);
+            }
+        }
+    }
+
+    #[test]
+    fn json_encodes_bytes_as_base64() {
+        let request = Envelope::new(CommandRequest::Other(b"hi".to_vec()));
+        let bytes = encode_serde(&CommandProtocol::Json, &request, DEFAULT_MAX_PAYLOAD_SIZE).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&bytes).unwrap(),
+            serde_json::json!({ "headers": {}, "body": { "Other": "aGk=" } })
+        );
+    }
+
+    #[test]
+    fn cbor_encodes_bytes_as_byte_string() {
+        use serde_cbor::Value as Cbor;
+        let request = Envelope::new(CommandRequest::Other(b"hi".to_vec()));
+        let bytes = encode_serde(&CommandProtocol::Cbor, &request, DEFAULT_MAX_PAYLOAD_SIZE).unwrap();
+        let body = match serde_cbor::from_slice::<Cbor>(&bytes).unwrap() {
+            Cbor::Map(mut envelope) => envelope.remove(&Cbor::Text("body".into())),
+            value => panic!("expected a map: {:?}", value),
+        };
+        let other = match body {
+            Some(Cbor::Map(mut body)) => body.remove(&Cbor::Text("Other".into())),
+            body => panic!("expected a map: {:?}", body),
+        };
+        assert_eq!(other, Some(Cbor::Bytes(b"hi".to_vec())));
+    }
+
+    #[test]
+    fn decodes_bytes_as_arrays_of_numbers() {
+        let json = br#"{"body":{"Put":{"key":"key","value":[104,105]}}}"#;
+        assert_eq!(
+            decode_serde::<Envelope<CommandRequest>>(&CommandProtocol::Json, json).unwrap(),
+            Envelope::new(CommandRequest::Put {
+                key: "key".to_string(),
+                value: b"hi".to_vec(),
+            })
+        );
+        let cbor = serde_cbor::to_vec(&serde_json::json!({ "body": { "Value": [104, 105] } })).unwrap();
+        assert_eq!(
+            decode_serde::<Envelope<CommandResponse>>(&CommandProtocol::Cbor, &cbor).unwrap(),
+            Envelope::new(CommandResponse::Value(b"hi".to_vec()))
+        );
+    }
+
+    #[test]
+    fn codec_reports_oversized_messages() {
+        let mut codec = CommandCodec::default();
+        let mut rejected = codec.rejected_messages();
+        codec.report_read_error("request", MessageTooLarge { size: 2, max: 1 }.into());
+        codec.report_read_error("request", invalid_data("malformed"));
+        let message = rejected.try_next().unwrap().unwrap();
+        assert_eq!(message.kind, "request");
+        assert!(rejected.try_next().is_err());
+    }
+}
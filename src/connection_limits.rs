@@ -0,0 +1,271 @@
+// Limits of the established connections of a node.
+// Pending connections and connections per peer are limited by the swarm itself. Established
+// inbound and outbound connections are counted here, if a new connection exceeds a limit, a peer
+// is chosen by the PrunePolicy whose connections are closed.
+
+use libp2p::{core::ConnectedPoint, PeerId};
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    time::Instant,
+};
+
+// Which peer is disconnected if there are too many connections.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PrunePolicy {
+    // the peer of the new connection
+    Newest,
+    // the peer that is connected the longest
+    Oldest,
+    // the peer with the lowest misbehaviour score, the newest peer if scores are equal
+    LowestScore,
+}
+
+impl FromStr for PrunePolicy {
+    type Err = String;
+
+    // Parse the policy name that is used on the command line.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "newest" => Ok(PrunePolicy::Newest),
+            "oldest" => Ok(PrunePolicy::Oldest),
+            "lowest-score" => Ok(PrunePolicy::LowestScore),
+            _ => Err(format!("unknown prune policy: {}", s)),
+        }
+    }
+}
+
+// Connection limits, None disables a limit.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimits {
+    pub max_inbound: Option<usize>,
+    pub max_outbound: Option<usize>,
+    pub max_per_peer: Option<usize>,
+    pub max_pending_incoming: Option<usize>,
+    pub max_pending_outgoing: Option<usize>,
+    pub prune_policy: PrunePolicy,
+}
+
+impl Default for ConnectionLimits {
+    // Servers accept many inbound connections, other nodes mostly dial.
+    fn default() -> Self {
+        if cfg!(feature = "server") {
+            ConnectionLimits {
+                max_inbound: Some(200),
+                max_outbound: Some(50),
+                max_per_peer: Some(2),
+                max_pending_incoming: Some(32),
+                max_pending_outgoing: Some(16),
+                prune_policy: PrunePolicy::LowestScore,
+            }
+        } else {
+            ConnectionLimits {
+                max_inbound: Some(25),
+                max_outbound: Some(25),
+                max_per_peer: Some(1),
+                max_pending_incoming: Some(8),
+                max_pending_outgoing: Some(8),
+                prune_policy: PrunePolicy::Newest,
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PeerConnections {
+    inbound: usize,
+    outbound: usize,
+    since: Instant,
+}
+
+// Established connections of all peers.
+#[derive(Debug)]
+pub struct ConnectionTracker {
+    limits: ConnectionLimits,
+    peers: HashMap<PeerId, PeerConnections>,
+    inbound: usize,
+    outbound: usize,
+}
+
+impl ConnectionTracker {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        ConnectionTracker {
+            limits,
+            peers: HashMap::new(),
+            inbound: 0,
+            outbound: 0,
+        }
+    }
+
+    pub fn limits(&self) -> &ConnectionLimits {
+        &self.limits
+    }
+
+    // Number of established inbound and outbound connections.
+    pub fn counts(&self) -> (usize, usize) {
+        (self.inbound, self.outbound)
+    }
+
+    pub fn is_connected(&self, peer: &PeerId) -> bool {
+        self.peers.contains_key(peer)
+    }
+
+    // Count the new connection, returns the peer whose connections should be closed if a limit is
+    // exceeded. score is the misbehaviour score of a peer.
+    pub fn on_established(
+        &mut self,
+        peer: &PeerId,
+        endpoint: &ConnectedPoint,
+        score: impl Fn(&PeerId) -> i32,
+    ) -> Option<PeerId> {
+        let connections = self.peers.entry(peer.clone()).or_insert_with(|| PeerConnections {
+            inbound: 0,
+            outbound: 0,
+            since: Instant::now(),
+        });
+        let is_inbound = endpoint.is_listener();
+        let (count, max) = if is_inbound {
+            connections.inbound += 1;
+            self.inbound += 1;
+            (self.inbound, self.limits.max_inbound)
+        } else {
+            connections.outbound += 1;
+            self.outbound += 1;
+            (self.outbound, self.limits.max_outbound)
+        };
+        if max.map_or(true, |max| count <= max) {
+            return None;
+        }
+        // only peers with connections in the same direction free a slot
+        let candidates = self.peers.iter().filter(|(_, connections)| {
+            if is_inbound {
+                connections.inbound > 0
+            } else {
+                connections.outbound > 0
+            }
+        });
+        match self.limits.prune_policy {
+            PrunePolicy::Newest => Some(peer.clone()),
+            PrunePolicy::Oldest => candidates
+                .min_by_key(|(_, connections)| connections.since)
+                .map(|(peer, _)| peer.clone()),
+            PrunePolicy::LowestScore => candidates
+                .min_by(|(a, a_connections), (b, b_connections)| {
+                    score(a)
+                        .cmp(&score(b))
+                        .then(b_connections.since.cmp(&a_connections.since))
+                })
+                .map(|(peer, _)| peer.clone()),
+        }
+    }
+
+    pub fn on_closed(&mut self, peer: &PeerId, endpoint: &ConnectedPoint) {
+        let connections = match self.peers.get_mut(peer) {
+            Some(connections) => connections,
+            None => return,
+        };
+        if endpoint.is_listener() {
+            connections.inbound = connections.inbound.saturating_sub(1);
+            self.inbound = self.inbound.saturating_sub(1);
+        } else {
+            connections.outbound = connections.outbound.saturating_sub(1);
+            self.outbound = self.outbound.saturating_sub(1);
+        }
+        if connections.inbound == 0 && connections.outbound == 0 {
+            self.peers.remove(peer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn inbound() -> ConnectedPoint {
+        ConnectedPoint::Listener {
+            local_addr: "/memory/1".parse().unwrap(),
+            send_back_addr: "/memory/2".parse().unwrap(),
+        }
+    }
+
+    fn outbound() -> ConnectedPoint {
+        ConnectedPoint::Dialer {
+            address: "/memory/3".parse().unwrap(),
+        }
+    }
+
+    fn tracker(max_inbound: usize, max_outbound: usize, prune_policy: PrunePolicy) -> ConnectionTracker {
+        ConnectionTracker::new(ConnectionLimits {
+            max_inbound: Some(max_inbound),
+            max_outbound: Some(max_outbound),
+            max_per_peer: None,
+            max_pending_incoming: None,
+            max_pending_outgoing: None,
+            prune_policy,
+        })
+    }
+
+    fn no_score(_: &PeerId) -> i32 {
+        0
+    }
+
+    #[test]
+    fn limits_each_direction_separately() {
+        let mut tracker = tracker(1, 1, PrunePolicy::Newest);
+        let (a, b, c) = (PeerId::random(), PeerId::random(), PeerId::random());
+        assert_eq!(tracker.on_established(&a, &inbound(), no_score), None);
+        assert_eq!(tracker.on_established(&b, &outbound(), no_score), None);
+        assert_eq!(tracker.counts(), (1, 1));
+        assert_eq!(tracker.on_established(&c, &inbound(), no_score), Some(c.clone()));
+        assert_eq!(tracker.counts(), (2, 1));
+    }
+
+    #[test]
+    fn closed_connections_free_their_slot() {
+        let mut tracker = tracker(1, 1, PrunePolicy::Newest);
+        let (a, b) = (PeerId::random(), PeerId::random());
+        tracker.on_established(&a, &inbound(), no_score);
+        assert!(tracker.is_connected(&a));
+        tracker.on_closed(&a, &inbound());
+        assert!(!tracker.is_connected(&a));
+        assert_eq!(tracker.counts(), (0, 0));
+        assert_eq!(tracker.on_established(&b, &inbound(), no_score), None);
+    }
+
+    #[test]
+    fn oldest_policy_prunes_longest_connected_peer() {
+        let mut tracker = tracker(2, 2, PrunePolicy::Oldest);
+        let (a, b, c, d) = (PeerId::random(), PeerId::random(), PeerId::random(), PeerId::random());
+        for (peer, endpoint) in &[(&a, outbound()), (&b, inbound()), (&c, inbound())] {
+            tracker.on_established(peer, endpoint, no_score);
+            // distinct connection times
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        // a is the oldest peer, but it does not free an inbound slot
+        assert_eq!(tracker.on_established(&d, &inbound(), no_score), Some(b));
+    }
+
+    #[test]
+    fn lowest_score_policy_prunes_worst_peer() {
+        let mut tracker = tracker(2, 2, PrunePolicy::LowestScore);
+        let (a, b, c) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let bad = b.clone();
+        let score = move |peer: &PeerId| if *peer == bad { -50 } else { 0 };
+        tracker.on_established(&a, &inbound(), &score);
+        tracker.on_established(&b, &inbound(), &score);
+        assert_eq!(tracker.on_established(&c, &inbound(), &score), Some(b));
+    }
+
+    #[test]
+    fn disabled_limits_are_never_exceeded() {
+        let mut tracker = ConnectionTracker::new(ConnectionLimits {
+            max_inbound: None,
+            max_outbound: None,
+            ..ConnectionLimits::default()
+        });
+        for _ in 0..100 {
+            assert_eq!(tracker.on_established(&PeerId::random(), &inbound(), no_score), None);
+        }
+    }
+}
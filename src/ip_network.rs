@@ -0,0 +1,123 @@
+// IP networks in CIDR notation, e.g. 192.168.1.0/24 or fd00::/8, that are used to filter the
+// addresses of peers discovered via mDNS.
+
+use libp2p::core::{multiaddr::Protocol, Multiaddr};
+use std::{fmt, net::IpAddr, str::FromStr};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+
+    // Whether the first ip4 or ip6 component of the address is in the network.
+    pub fn contains_addr(&self, addr: &Multiaddr) -> bool {
+        addr.iter()
+            .find_map(|protocol| match protocol {
+                Protocol::Ip4(ip) => Some(IpAddr::V4(ip)),
+                Protocol::Ip6(ip) => Some(IpAddr::V6(ip)),
+                _ => None,
+            })
+            .map_or(false, |ip| self.contains(&ip))
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    // Parse a network in CIDR notation, an address without prefix length is a single host.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '/');
+        let addr = parts
+            .next()
+            .unwrap_or_default()
+            .parse::<IpAddr>()
+            .map_err(|e| format!("invalid network {}: {}", s, e))?;
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match parts.next() {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_prefix_len)
+                .ok_or_else(|| format!("invalid prefix length in network {}", s))?,
+            None => max_prefix_len,
+        };
+        Ok(IpNetwork { addr, prefix_len })
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(s: &str) -> IpNetwork {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn contains_addresses_with_same_prefix() {
+        let network = network("192.168.1.0/24");
+        assert!(network.contains(&ip("192.168.1.0")));
+        assert!(network.contains(&ip("192.168.1.255")));
+        assert!(!network.contains(&ip("192.168.2.1")));
+        assert!(!network.contains(&ip("::ffff:192.168.1.1")));
+
+        let network = network("fd00::/8");
+        assert!(network.contains(&ip("fd12:3456::1")));
+        assert!(!network.contains(&ip("fe80::1")));
+        assert!(!network.contains(&ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn prefix_length_bounds() {
+        assert!(network("0.0.0.0/0").contains(&ip("203.0.113.7")));
+        assert!(network("::/0").contains(&ip("2001:db8::1")));
+        assert!(network("10.0.0.1/32").contains(&ip("10.0.0.1")));
+        assert!(!network("10.0.0.1/32").contains(&ip("10.0.0.2")));
+        // addresses without prefix length are single hosts
+        assert_eq!(network("10.0.0.1"), network("10.0.0.1/32"));
+        assert!(network("::1").contains(&ip("::1")));
+        assert!(!network("::1").contains(&ip("::2")));
+    }
+
+    #[test]
+    fn rejects_invalid_networks() {
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("::/129".parse::<IpNetwork>().is_err());
+        assert!("10.0.0.0/x".parse::<IpNetwork>().is_err());
+        assert!("localhost/8".parse::<IpNetwork>().is_err());
+    }
+
+    #[test]
+    fn contains_addr_uses_ip_component() {
+        let network = network("192.168.1.0/24");
+        assert!(network.contains_addr(&"/ip4/192.168.1.10/tcp/4001".parse().unwrap()));
+        assert!(!network.contains_addr(&"/ip4/10.0.0.1/tcp/4001".parse().unwrap()));
+        assert!(!network.contains_addr(&"/dns4/example.com/tcp/4001".parse().unwrap()));
+    }
+}
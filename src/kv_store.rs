@@ -0,0 +1,185 @@
+// Local key-value store of a node, that remote peers access with the Get, Put, Delete and List
+// commands of the command protocol.
+// The number of keys, the size of single values and the total size of keys and values are
+// limited, so that remote peers can not exhaust the memory of the node.
+
+use std::{collections::BTreeMap, fmt};
+
+#[derive(Debug, Clone, Copy)]
+pub struct StoreLimits {
+    pub max_keys: usize,
+    pub max_value_size: usize,
+    // sum of the sizes of all keys and values
+    pub max_total_size: usize,
+}
+
+impl Default for StoreLimits {
+    fn default() -> Self {
+        StoreLimits {
+            max_keys: 1024,
+            max_value_size: 64 * 1024,
+            max_total_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+// Reason why a value was not stored.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StoreError {
+    ValueTooLarge { size: usize, max: usize },
+    TooManyKeys { max: usize },
+    StoreFull { max: usize },
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::ValueTooLarge { size, max } => {
+                write!(f, "value of {} bytes exceeds the limit of {} bytes", size, max)
+            }
+            StoreError::TooManyKeys { max } => write!(f, "store is limited to {} keys", max),
+            StoreError::StoreFull { max } => write!(f, "store is limited to {} bytes", max),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+#[derive(Debug, Default)]
+pub struct KvStore {
+    entries: BTreeMap<String, Vec<u8>>,
+    limits: StoreLimits,
+    total_size: usize,
+}
+
+impl KvStore {
+    pub fn new(limits: StoreLimits) -> Self {
+        KvStore {
+            entries: BTreeMap::new(),
+            limits,
+            total_size: 0,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Vec<u8>> {
+        self.entries.get(key)
+    }
+
+    // Insert the value, returns the previous value for the key. Fails without changing the store
+    // if the value exceeds one of the limits.
+    pub fn put(&mut self, key: String, value: Vec<u8>) -> Result<Option<Vec<u8>>, StoreError> {
+        if value.len() > self.limits.max_value_size {
+            return Err(StoreError::ValueTooLarge {
+                size: value.len(),
+                max: self.limits.max_value_size,
+            });
+        }
+        let previous_size = match self.entries.get(&key) {
+            Some(previous) => key.len() + previous.len(),
+            None if self.entries.len() >= self.limits.max_keys => {
+                return Err(StoreError::TooManyKeys {
+                    max: self.limits.max_keys,
+                })
+            }
+            None => 0,
+        };
+        let total_size = self.total_size - previous_size + key.len() + value.len();
+        if total_size > self.limits.max_total_size {
+            return Err(StoreError::StoreFull {
+                max: self.limits.max_total_size,
+            });
+        }
+        self.total_size = total_size;
+        Ok(self.entries.insert(key, value))
+    }
+
+    pub fn delete(&mut self, key: &str) -> Option<Vec<u8>> {
+        let value = self.entries.remove(key)?;
+        self.total_size -= key.len() + value.len();
+        Some(value)
+    }
+
+    // All keys that start with prefix, in ascending order.
+    pub fn list(&self, prefix: &str) -> Vec<String> {
+        self.entries
+            .range(prefix.to_string()..)
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(max_keys: usize, max_value_size: usize, max_total_size: usize) -> KvStore {
+        KvStore::new(StoreLimits {
+            max_keys,
+            max_value_size,
+            max_total_size,
+        })
+    }
+
+    #[test]
+    fn replacing_a_value_updates_the_total_size() {
+        let mut store = store(10, 100, 100);
+        assert_eq!(store.put("key".into(), vec![0; 10]), Ok(None));
+        assert_eq!(store.total_size, 13);
+        assert_eq!(store.put("key".into(), vec![1; 5]), Ok(Some(vec![0; 10])));
+        assert_eq!(store.total_size, 8);
+        assert_eq!(store.get("key"), Some(&vec![1; 5]));
+    }
+
+    #[test]
+    fn delete_frees_the_size_of_key_and_value() {
+        let mut store = store(10, 100, 100);
+        store.put("a".into(), vec![0; 10]).unwrap();
+        store.put("b".into(), vec![0; 20]).unwrap();
+        assert_eq!(store.delete("a"), Some(vec![0; 10]));
+        assert_eq!(store.delete("a"), None);
+        assert_eq!(store.total_size, 21);
+    }
+
+    #[test]
+    fn rejects_values_above_max_value_size() {
+        let mut store = store(10, 4, 100);
+        assert_eq!(
+            store.put("key".into(), vec![0; 5]),
+            Err(StoreError::ValueTooLarge { size: 5, max: 4 })
+        );
+        assert_eq!(store.get("key"), None);
+    }
+
+    #[test]
+    fn max_keys_only_limits_new_keys() {
+        let mut store = store(2, 100, 100);
+        store.put("a".into(), vec![0]).unwrap();
+        store.put("b".into(), vec![0]).unwrap();
+        assert_eq!(store.put("c".into(), vec![0]), Err(StoreError::TooManyKeys { max: 2 }));
+        assert_eq!(store.put("a".into(), vec![1]), Ok(Some(vec![0])));
+        store.delete("b");
+        assert_eq!(store.put("c".into(), vec![0]), Ok(None));
+    }
+
+    #[test]
+    fn rejects_values_that_exceed_max_total_size() {
+        let mut store = store(10, 100, 20);
+        store.put("a".into(), vec![0; 10]).unwrap();
+        assert_eq!(store.put("b".into(), vec![0; 10]), Err(StoreError::StoreFull { max: 20 }));
+        assert_eq!(store.total_size, 11);
+        // replacing a value only counts the difference in size
+        assert_eq!(store.put("a".into(), vec![1; 19]), Ok(Some(vec![0; 10])));
+        assert_eq!(store.total_size, 20);
+    }
+
+    #[test]
+    fn lists_keys_with_prefix_in_order() {
+        let mut store = store(10, 100, 100);
+        for key in &["b2", "a", "b1", "c"] {
+            store.put(key.to_string(), vec![]).unwrap();
+        }
+        assert_eq!(store.list("b"), vec!["b1".to_string(), "b2".to_string()]);
+    }
+}
@@ -1,5 +1,17 @@
-use crate::command_protocol::{CommandCodec, CommandProtocol, CommandRequest};
-use crate::network_behaviour::P2PNetworkBehaviour;
+use crate::bootstrap::BootstrapSchedule;
+use crate::command_handler::TrustPingHandler;
+use crate::command_protocol::{
+    CodecConfig, CommandCodec, CommandProtocol, CommandRequest, DEFAULT_MAX_PAYLOAD_SIZE,
+};
+use crate::connection_limits::{ConnectionLimits, ConnectionTracker};
+use crate::ip_network::IpNetwork;
+use crate::kv_store::{KvStore, StoreLimits};
+use crate::network_behaviour::{P2PNetworkBehaviour, RequestTimeouts, MAX_PING_FAILURES};
+use crate::node_event::NodeEvent;
+use crate::peer_score::BanChange;
+use crate::rate_limit::{RateLimitConfig, RateLimiter};
+use crate::retry::{FailureClass, RetryPolicy};
+use crate::node_store::NodeStore;
 use async_std::{
     io::{stdin, BufReader},
     task,
@@ -8,20 +20,29 @@ use futures::{future, prelude::*};
 use libp2p::{
     build_development_transport,
     core::Multiaddr,
+    gossipsub::{Gossipsub, GossipsubConfigBuilder, GossipsubMessage, MessageAuthenticity, MessageId, Topic},
+    identify::Identify,
     identity::Keypair,
-    kad::{record::store::MemoryStore, Kademlia},
+    kad::{
+        record::{store::MemoryStoreConfig, Key},
+        Kademlia, KademliaConfig, Quorum, Record,
+    },
 
     mdns::Mdns,
+    ping::{Ping, PingConfig},
     request_response::{ProtocolSupport, RequestResponse, RequestResponseConfig},
-    swarm::{ExpandedSwarm, IntoProtocolsHandler, NetworkBehaviour, ProtocolsHandler},
+    swarm::{ExpandedSwarm, IntoProtocolsHandler, NetworkBehaviour, ProtocolsHandler, SwarmBuilder, SwarmEvent},
     PeerId, Swarm,
 };
 use std::{
+    collections::hash_map::DefaultHasher,
     error::Error,
-    iter,
+    hash::{Hash, Hasher},
+    num::{NonZeroU32, NonZeroUsize},
     str::{FromStr, SplitWhitespace},
     string::String,
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
 use identity_core::did::{DID};
@@ -33,8 +54,27 @@ use identity_comm::DIDComm_message;
 mod dht_proto {
     include!(concat!(env!("OUT_DIR"), "/dht.pb.rs"));
 }
+mod bootstrap;
+mod command_handler;
 mod command_protocol;
+mod connection_limits;
+mod ip_network;
+mod kv_store;
 mod network_behaviour;
+mod node_event;
+mod node_store;
+mod peer_score;
+mod peer_stats;
+mod rate_limit;
+mod retry;
+
+const IDENTIFY_PROTOCOL_VERSION: &str = "/iota-p2p-poc/1.0.0";
+
+// Re-announce provider records every 12 hours
+const DEFAULT_REPROVIDE_INTERVAL: u64 = 12 * 60 * 60;
+
+// Refresh the routing table every 5 minutes
+const DEFAULT_BOOTSTRAP_INTERVAL: u64 = 5 * 60;
 
 type P2PNetworkSwarm = ExpandedSwarm<
     P2PNetworkBehaviour,
@@ -59,32 +99,147 @@ fn main() -> Result<(), Box<dyn Error>> {
     .unwrap();
     println!("did: {}", did.to_string());
 
+    let local_public_key = local_keys.public();
+    let gossipsub_keys = local_keys.clone();
+
     // create a transport
     let transport = build_development_transport(local_keys)?;
 
+    // seconds after which idle connections are closed, applies to the command protocol and to
+    // kademlia, both default to 10 seconds
+    let keep_alive = match arg_value("--keep-alive") {
+        Some(secs) => Some(Duration::from_secs(secs.parse()?)),
+        None => None,
+    };
+
     // Create a Kademlia behaviour.
     let kademlia = {
-        let store = MemoryStore::new(local_peer_id.clone());
-        Kademlia::new(local_peer_id.clone(), store)
+        let store = node_store(local_peer_id.clone())?;
+        let mut cfg = KademliaConfig::default();
+        // interval in seconds in which provider records are re-announced, "off" disables it
+        let reprovide_interval = optional_arg("--reprovide-interval", Some(DEFAULT_REPROVIDE_INTERVAL))?;
+        cfg.set_provider_publication_interval(reprovide_interval.map(Duration::from_secs));
+        if let Some(keep_alive) = keep_alive {
+            cfg.set_connection_idle_timeout(keep_alive);
+        }
+        Kademlia::with_config(local_peer_id.clone(), store, cfg)
+    };
+    // mDNS discovers peers in the local network, it is disabled on servers since cloud networks
+    // must not be flooded with multicast traffic. --mdns / --no-mdns override the default.
+    let mdns_enabled = if cfg!(feature = "server") {
+        std::env::args().any(|arg| arg == "--mdns")
+    } else {
+        !std::env::args().any(|arg| arg == "--no-mdns")
+    };
+    let mdns = if mdns_enabled { Some(Mdns::new()?) } else { None };
+
+    // Create an Identify behaviour to exchange listen addresses, agent version and protocols
+    let identify = Identify::new(
+        IDENTIFY_PROTOCOL_VERSION.into(),
+        format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        local_public_key,
+    );
+
+    // Create a Ping behaviour that periodically measures the round-trip time to connected peers.
+    // It does not keep idle connections alive, so that --keep-alive decides when they are closed.
+    // Ping closes the connection after max_failures consecutive failures, the limit is above
+    // MAX_PING_FAILURES so that the failures are reported before the connection and the ping
+    // statistics of the peer are dropped.
+    let ping = Ping::new(PingConfig::new().with_max_failures(NonZeroU32::new(MAX_PING_FAILURES + 1).unwrap()));
+
+    // Create a Gossipsub behaviour for publish / subscribe, messages are deduplicated by the hash
+    // of their content and only forwarded after they were validated
+    let gossipsub = {
+        let cfg = GossipsubConfigBuilder::new()
+            .message_id_fn(content_message_id)
+            .validate_messages()
+            .build();
+        Gossipsub::new(MessageAuthenticity::Signed(gossipsub_keys), cfg)
     };
-    let mdns = Mdns::new()?;
 
     // Create RequestResponse behaviour with CommandProtocol
-    let msg_proto = {
-        // set request_timeout and connection_keep_alive if necessary
-        let cfg = RequestResponseConfig::default();
-        let protocols = iter::once((CommandProtocol(), ProtocolSupport::Full));
-        RequestResponse::new(CommandCodec(), protocols, cfg)
+    let request_timeouts = request_timeouts()?;
+    let (msg_proto, rejected_messages) = {
+        // shorter timeouts of single commands are enforced by P2PNetworkBehaviour
+        let mut cfg = RequestResponseConfig::default();
+        cfg.set_request_timeout(request_timeouts.max());
+        if let Some(keep_alive) = keep_alive {
+            cfg.set_connection_keep_alive(keep_alive);
+        }
+        // e.g. --protocols v2,json to only advertise a subset of the supported protocols
+        let protocols = match arg_value("--protocols") {
+            Some(names) => names
+                .split(',')
+                .map(CommandProtocol::from_str)
+                .collect::<Result<Vec<_>, _>>()?,
+            None => CommandProtocol::supported().collect(),
+        };
+        let protocols = protocols.into_iter().map(|protocol| (protocol, ProtocolSupport::Full));
+        let mut codec = CommandCodec::new(codec_config()?);
+        let rejected_messages = codec.rejected_messages();
+        (RequestResponse::new(codec, protocols, cfg), rejected_messages)
     };
     // Create a Swarm that establishes connections through the given transport
     // Use custom behaviour P2PNetworkBehaviour
     let mut swarm = {
-        let behaviour = P2PNetworkBehaviour {
-            kademlia,
-            mdns,
-            msg_proto,
-        };
-        Swarm::new(transport, behaviour, local_peer_id)
+        let limits = connection_limits()?;
+        let mut behaviour = P2PNetworkBehaviour::new(kademlia, mdns.into(), msg_proto, identify, ping, gossipsub);
+        behaviour.rejected_messages = Some(rejected_messages);
+        // e.g. --mdns-filter 192.168.1.0/24 to ignore mDNS discoveries with addresses outside of
+        // that network. It only filters the discovered addresses, mDNS queries are still sent
+        // and answered on all interfaces.
+        if let Some(networks) = arg_value("--mdns-filter") {
+            behaviour.mdns_filter = networks
+                .split(',')
+                .map(IpNetwork::from_str)
+                .collect::<Result<Vec<_>, _>>()?;
+        }
+        behaviour.connections = ConnectionTracker::new(limits.clone());
+        behaviour.rate_limiter = rate_limiter()?;
+        behaviour.store = KvStore::new(store_limits()?);
+        // e.g. --store-writers <peer_id>,<peer_id> to only allow these peers to put and delete values
+        if let Some(writers) = arg_value("--store-writers") {
+            behaviour.store_writers = Some(
+                writers
+                    .split(',')
+                    .map(PeerId::from_str)
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| format!("invalid peer id in --store-writers: {}", writers))?,
+            );
+        }
+        behaviour.request_timeouts = request_timeouts;
+        behaviour.retry_policy = retry_policy()?;
+        // interval in seconds in which the routing table is refreshed, "off" disables it
+        let bootstrap_interval = optional_arg("--bootstrap-interval", Some(DEFAULT_BOOTSTRAP_INTERVAL))?;
+        behaviour.bootstrap = BootstrapSchedule::new(bootstrap_interval.map(Duration::from_secs));
+        if let Some(interval) = bootstrap_interval {
+            // peers discovered via mDNS are refreshed as well
+            behaviour.bootstrap.schedule(Duration::from_secs(interval));
+        }
+        behaviour.register_handler(TRUSTPING.to_string(), TrustPingHandler);
+        if let Some(i) = std::env::args().position(|arg| arg == "--app-id") {
+            behaviour.app_id = std::env::args().nth(i + 1);
+        }
+        // e.g. --forward-for <peer_id>,<peer_id> to forward the FORWARD requests of these peers to
+        // their target, forwarding is disabled by default
+        if let Some(peers) = arg_value("--forward-for") {
+            behaviour.forward_for = peers
+                .split(',')
+                .map(PeerId::from_str)
+                .collect::<Result<_, _>>()
+                .map_err(|_| format!("invalid peer id in --forward-for: {}", peers))?;
+        }
+        let mut builder = SwarmBuilder::new(transport, behaviour, local_peer_id);
+        if let Some(max) = limits.max_pending_incoming {
+            builder = builder.incoming_connection_limit(max);
+        }
+        if let Some(max) = limits.max_pending_outgoing {
+            builder = builder.outgoing_connection_limit(max);
+        }
+        if let Some(max) = limits.max_per_peer {
+            builder = builder.peer_connection_limit(max);
+        }
+        builder.build()
     };
 
     let mut is_swarm_listening = false;
@@ -110,6 +265,179 @@ fn main() -> Result<(), Box<dyn Error>> {
     poll_input(swarm)
 }
 
+// Value that follows the flag name in the command line arguments
+fn arg_value(name: &str) -> Option<String> {
+    let i = std::env::args().position(|arg| arg == name)?;
+    std::env::args().nth(i + 1)
+}
+
+// Parse the value of the flag, "off" results in None
+fn optional_arg<T>(name: &str, default: Option<T>) -> Result<Option<T>, Box<dyn Error>>
+where
+    T: FromStr,
+    T::Err: Error + 'static,
+{
+    match arg_value(name).as_deref() {
+        Some("off") => Ok(None),
+        Some(value) => Ok(Some(value.parse()?)),
+        None => Ok(default),
+    }
+}
+
+// Limits of the command protocol codec, configured with
+// --max-request-size <bytes>, --max-response-size <bytes>, --read-timeout <secs|off> and
+// --compression-threshold <bytes|off>. --max-payload <bytes> sets the default of both size
+// limits. The size limits can not be disabled, so that peers can not force unbounded allocations.
+fn codec_config() -> Result<CodecConfig, Box<dyn Error>> {
+    let default = CodecConfig::default();
+    let max_payload_size = match arg_value("--max-payload") {
+        Some(size) => size.parse()?,
+        None => DEFAULT_MAX_PAYLOAD_SIZE,
+    };
+    let max_request_size = match arg_value("--max-request-size") {
+        Some(size) => size.parse()?,
+        None => max_payload_size,
+    };
+    let max_response_size = match arg_value("--max-response-size") {
+        Some(size) => size.parse()?,
+        None => max_payload_size,
+    };
+    let read_timeout = optional_arg("--read-timeout", default.read_timeout.map(|t| t.as_secs()))?;
+    Ok(CodecConfig {
+        max_request_size,
+        max_response_size,
+        read_timeout: read_timeout.map(Duration::from_secs),
+        compression_threshold: optional_arg("--compression-threshold", default.compression_threshold)?,
+    })
+}
+
+// Connection limits, the defaults depend on the server feature. Configured with
+// --max-inbound, --max-outbound, --max-per-peer, --max-pending-incoming and
+// --max-pending-outgoing <n|off> and --prune-policy <newest|oldest|lowest-score>
+fn connection_limits() -> Result<ConnectionLimits, Box<dyn Error>> {
+    let default = ConnectionLimits::default();
+    Ok(ConnectionLimits {
+        max_inbound: optional_arg("--max-inbound", default.max_inbound)?,
+        max_outbound: optional_arg("--max-outbound", default.max_outbound)?,
+        max_per_peer: optional_arg("--max-per-peer", default.max_per_peer)?,
+        max_pending_incoming: optional_arg("--max-pending-incoming", default.max_pending_incoming)?,
+        max_pending_outgoing: optional_arg("--max-pending-outgoing", default.max_pending_outgoing)?,
+        prune_policy: match arg_value("--prune-policy") {
+            Some(policy) => policy.parse()?,
+            None => default.prune_policy,
+        },
+    })
+}
+
+// Timeouts of outbound requests, configured with --request-timeout <secs> and per command type
+// with e.g. --command-timeout GET=5,FORWARD=30. Requests time out after 10 seconds by default, the
+// same default as in libp2p request-response. Requests that are sent through a forwarding peer
+// use the FORWARD timeout, by default twice the timeout of the forwarded command.
+fn request_timeouts() -> Result<RequestTimeouts, Box<dyn Error>> {
+    let mut timeouts = RequestTimeouts::default();
+    if let Some(timeout) = arg_value("--request-timeout") {
+        timeouts.default = Duration::from_secs(timeout.parse()?);
+    }
+    if let Some(overrides) = arg_value("--command-timeout") {
+        for command_timeout in overrides.split(',') {
+            let mut parts = command_timeout.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(command), Some(timeout)) => {
+                    let timeout = Duration::from_secs(timeout.parse()?);
+                    timeouts.commands.insert(command.to_uppercase(), timeout);
+                }
+                _ => return Err(format!("invalid command timeout: {}", command_timeout).into()),
+            }
+        }
+    }
+    Ok(timeouts)
+}
+
+// Retries of failed outbound requests are disabled by default, they are enabled with
+// --retry <max attempts> and configured with --retry-backoff <ms> and e.g.
+// --retry-on dial,timeout,closed
+fn retry_policy() -> Result<Option<RetryPolicy>, Box<dyn Error>> {
+    let mut policy = RetryPolicy::default();
+    policy.max_attempts = match optional_arg("--retry", None)? {
+        Some(max_attempts) => max_attempts,
+        None => return Ok(None),
+    };
+    if let Some(backoff) = arg_value("--retry-backoff") {
+        policy.initial_backoff = Duration::from_millis(backoff.parse()?);
+    }
+    if let Some(classes) = arg_value("--retry-on") {
+        policy.retry_on = classes
+            .split(',')
+            .map(FailureClass::from_str)
+            .collect::<Result<Vec<_>, _>>()?;
+    }
+    Ok(Some(policy))
+}
+
+// Rate limit of the inbound requests per peer and command type, configured with
+// --rate-limit <requests per second|off> and --rate-limit-burst <n>. Commands with own limits
+// keep their defaults. Peers that exceed the limit are penalised for spam.
+fn rate_limiter() -> Result<Option<RateLimiter>, Box<dyn Error>> {
+    let mut config = RateLimitConfig::default();
+    config.default.refill_rate = match optional_arg("--rate-limit", Some(config.default.refill_rate))? {
+        Some(refill_rate) => refill_rate,
+        None => return Ok(None),
+    };
+    if let Some(capacity) = arg_value("--rate-limit-burst") {
+        config.default.capacity = capacity.parse()?;
+    }
+    config.default.validate()?;
+    Ok(Some(RateLimiter::new(config)))
+}
+
+// Limits of the key-value store that remote peers access, configured with --store-max-keys <n>,
+// --store-max-value-size <bytes> and --store-max-size <bytes>
+fn store_limits() -> Result<StoreLimits, Box<dyn Error>> {
+    let mut limits = StoreLimits::default();
+    if let Some(max_keys) = arg_value("--store-max-keys") {
+        limits.max_keys = max_keys.parse()?;
+    }
+    if let Some(max_value_size) = arg_value("--store-max-value-size") {
+        limits.max_value_size = max_value_size.parse()?;
+    }
+    if let Some(max_total_size) = arg_value("--store-max-size") {
+        limits.max_total_size = max_total_size.parse()?;
+    }
+    Ok(limits)
+}
+
+// Kademlia record store, configured with --store-path <file> to persist the records and
+// --max-records <n> / --max-record-size <bytes> to limit the stored records
+fn node_store(local_peer_id: PeerId) -> Result<NodeStore, Box<dyn Error>> {
+    let mut config = MemoryStoreConfig::default();
+    if let Some(max_records) = arg_value("--max-records") {
+        config.max_records = max_records.parse()?;
+    }
+    if let Some(max_record_size) = arg_value("--max-record-size") {
+        config.max_value_bytes = max_record_size.parse()?;
+    }
+    match arg_value("--store-path") {
+        Some(path) => {
+            println!("Persisting DHT records in {}", path);
+            Ok(NodeStore::open(local_peer_id, config, path)?)
+        }
+        None => Ok(NodeStore::in_memory(local_peer_id, config)),
+    }
+}
+
+// Messages of the same publisher with the same sequence number and content get the same id and
+// are only delivered once
+fn content_message_id(message: &GossipsubMessage) -> MessageId {
+    let mut hasher = DefaultHasher::new();
+    message.source.hash(&mut hasher);
+    message.sequence_number.hash(&mut hasher);
+    message.data.hash(&mut hasher);
+    MessageId::from(hasher.finish().to_string())
+}
+
+// Only directly dialable addresses are supported, libp2p 0.28 has no circuit relay transport.
+// Peers behind a NAT are reached with FORWARD requests through a peer that was started with
+// --forward-for.
 fn attempt_dialing_remote(swarm: &mut P2PNetworkSwarm) {
     if let Some(i) = std::env::args().position(|arg| arg == "--dial") {
         // Dial peer at fixed addr to connect to p2p network
@@ -119,12 +447,7 @@ fn attempt_dialing_remote(swarm: &mut P2PNetworkSwarm) {
                     println!("Dialed {}", addr);
                     if let Some(peer_id) = std::env::args().nth(i + 2) {
                         if let Ok(peer) = PeerId::from_str(&*peer_id) {
-                            swarm.kademlia.add_address(&peer, remote);
-                            if swarm.kademlia.bootstrap().is_ok() {
-                                println!("Successful bootstrapping");
-                            } else {
-                                eprintln!("Could not bootstrap");
-                            }
+                            swarm.add_bootstrap_peer(peer, remote);
                         } else {
                             eprintln!("Invalid Peer Id {}", peer_id);
                         }
@@ -143,6 +466,7 @@ fn attempt_dialing_remote(swarm: &mut P2PNetworkSwarm) {
 
 fn poll_input(mut swarm: P2PNetworkSwarm) -> Result<(), Box<dyn Error>> {
     let mut stdin = BufReader::new(stdin()).lines();
+    let mut events = swarm.event_stream();
     let mut listening = false;
     task::block_on(future::poll_fn(move |cx: &mut Context<'_>| {
         loop {
@@ -154,11 +478,32 @@ fn poll_input(mut swarm: P2PNetworkSwarm) -> Result<(), Box<dyn Error>> {
             }
         }
         loop {
-            match swarm.poll_next_unpin(cx) {
-                Poll::Ready(Some(event)) => println!("{:?}", event),
-                Poll::Ready(None) => {
-                    return Poll::Ready(Ok(()));
+            let event = {
+                let next_event = swarm.next_event();
+                futures::pin_mut!(next_event);
+                next_event.poll(cx)
+            };
+            match event {
+                Poll::Ready(SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. }) => {
+                    if let Some(peer) = swarm.connection_established(&peer_id, &endpoint) {
+                        swarm.emit(NodeEvent::ConnectionLimitReached { peer: peer.clone() });
+                        disconnect_peer(&mut swarm, peer);
+                    }
+                    swarm.emit(NodeEvent::ConnectionEstablished {
+                        peer: peer_id,
+                        endpoint,
+                    });
                 }
+                Poll::Ready(SwarmEvent::ConnectionClosed { peer_id, endpoint, .. }) => {
+                    swarm.connection_closed(&peer_id, &endpoint);
+                    swarm.emit(NodeEvent::ConnectionClosed {
+                        peer: peer_id,
+                        endpoint,
+                    });
+                }
+                Poll::Ready(event) => swarm.emit(NodeEvent::Swarm {
+                    description: format!("{:?}", event),
+                }),
                 Poll::Pending => {
                     if !listening {
                         for a in Swarm::listeners(&swarm) {
@@ -168,21 +513,246 @@ fn poll_input(mut swarm: P2PNetworkSwarm) -> Result<(), Box<dyn Error>> {
                         println!("Type LIST to view current bucket entries");
                         println!("Type PING <peer_id> to ping another peer");
                         println!("Type CMD <peer_id> <message> to send a command / message to another peer");
+                        println!("Type UNREGISTER <command> to stop handling a command type, other peers get an error for it");
+                        println!("Type PUT <key> <value> [quorum] to store a record in the DHT");
+                        println!("Type GET <key> [quorum] to get a record from the DHT, quorum is one of one, majority, all or a number");
+                        println!("Type PROVIDE <key> / STOPPROVIDE <key> to start / stop announcing that this node provides the key");
+                        println!("Type FINDPROVIDERS <key> to search the providers of a key, PROVIDING to view the provided keys");
+                        println!("Type PEERINFO <peer_id> to view the agent version, addresses and protocols of a peer");
+                        println!("Type SUBSCRIBE <topic> / UNSUBSCRIBE <topic> to join / leave a topic, PUBLISH <topic> <message> to broadcast a message");
+                        println!("Type FORWARD <forwarding_peer_id> <peer_id> to send requests to a peer through a forwarding peer, DIRECT <peer_id> to send them directly again");
+                        println!("Type BAN <peer_id> [secs] / UNBAN <peer_id> to ban / unban a peer, BANS to view the banned peers");
+                        println!("Type STATS to view round-trip times and ping failures of peers");
+                        println!("Type PENDING to view requests that are waiting for a response");
+                        println!("Type KVGET <peer_id> <key>, KVPUT <peer_id> <key> <value>, KVDEL <peer_id> <key> or KVLIST <peer_id> [prefix] to access the store of another peer");
                     }
                     break;
                 }
             }
         }
+        // refuse connections of banned peers
+        while let Some(change) = swarm.next_ban_change() {
+            match change {
+                BanChange::Banned(peer) => {
+                    Swarm::ban_peer_id(&mut swarm, peer.clone());
+                    let score = swarm.peer_score(&peer);
+                    swarm.emit(NodeEvent::PeerBanned { peer, score });
+                }
+                BanChange::Unbanned(peer) => {
+                    Swarm::unban_peer_id(&mut swarm, peer.clone());
+                    swarm.emit(NodeEvent::PeerUnbanned { peer });
+                }
+            }
+        }
+        while let Poll::Ready(Some(event)) = events.poll_next_unpin(cx) {
+            if event.is_failure() {
+                eprintln!("{}", event);
+            } else {
+                println!("{}", event);
+            }
+        }
         Poll::Pending
     }))
 }
 
+// Close all connections to the peer, the swarm only supports this by banning and unbanning the
+// peer. Banned peers are skipped, their connections are closed when the ban is applied and the
+// ban must not be lifted here.
+fn disconnect_peer(swarm: &mut P2PNetworkSwarm, peer: PeerId) {
+    if swarm.is_banned(&peer) {
+        return;
+    }
+    Swarm::ban_peer_id(swarm, peer.clone());
+    Swarm::unban_peer_id(swarm, peer);
+}
+
 fn handle_input_line(swarm: &mut P2PNetworkSwarm, line: String) {
     let mut args = line.split_whitespace();
     match args.next() {
-        Some("PING") => send_ping_to_peer(args, &mut swarm.msg_proto),
-        Some("TRUSTPING") => send_trustping_to_peer(args, &mut swarm.msg_proto),
-        Some("CMD") => send_cmd_to_peer(args, &mut swarm.msg_proto),
+        Some("PING") => send_ping_to_peer(args, swarm),
+        Some("TRUSTPING") => send_trustping_to_peer(args, swarm),
+        Some("CMD") => send_cmd_to_peer(args, swarm),
+        Some("UNREGISTER") => match args.next() {
+            Some(command) => {
+                if swarm.unregister_handler(command) {
+                    println!("Unregistered handler for {}", command);
+                } else {
+                    println!("No handler registered for {}", command);
+                }
+            }
+            None => println!("Expected command"),
+        },
+        Some(cmd @ "KVGET") | Some(cmd @ "KVPUT") | Some(cmd @ "KVDEL") | Some(cmd @ "KVLIST") => {
+            send_kv_cmd_to_peer(cmd, args, swarm)
+        }
+        Some("PUT") => put_record(args, &mut swarm.kademlia),
+        Some("GET") => get_record(args, &mut swarm.kademlia),
+        Some("PROVIDE") => match args.next() {
+            Some(key) => match swarm.start_providing(Key::new(&key)) {
+                Ok(query_id) => println!("Announcing provider record with query {:?}", query_id),
+                Err(e) => eprintln!("Failed to store provider record locally: {:?}", e),
+            },
+            None => println!("Expected key"),
+        },
+        Some("STOPPROVIDE") => match args.next() {
+            Some(key) => swarm.stop_providing(&Key::new(&key)),
+            None => println!("Expected key"),
+        },
+        Some("FINDPROVIDERS") => match args.next() {
+            Some(key) => {
+                let (query_id, providers) = swarm.get_providers_with_receiver(Key::new(&key));
+                println!("Searching providers with query {:?}", query_id);
+                let key = key.to_string();
+                task::spawn(async move {
+                    match providers.await {
+                        Ok(Ok(providers)) => println!("Providers of {:?}: {:?}", key, providers),
+                        Ok(Err(error)) => eprintln!("Could not find providers of {:?}: {:?}", key, error),
+                        Err(_) => {}
+                    }
+                });
+            }
+            None => println!("Expected key"),
+        },
+        Some("PROVIDING") => {
+            println!("Provided Keys:");
+            for key in swarm.provided_keys() {
+                println!("key: {:?}", String::from_utf8_lossy(key.as_ref()));
+            }
+        }
+        Some("PEERINFO") => match args.next().map(PeerId::from_str) {
+            Some(Ok(peer)) => match swarm.peer_info(&peer) {
+                Some(info) => {
+                    println!("Agent version: {}", info.agent_version);
+                    println!("Protocol version: {}", info.protocol_version);
+                    println!("Listen addresses: {:?}", info.listen_addrs);
+                    println!("Protocols: {:?}", info.protocols);
+                }
+                None => println!("No info for peer {:?}", peer),
+            },
+            Some(Err(_)) => println!("Faulty target peer id"),
+            None => println!("Expected target peer id"),
+        },
+        Some("SUBSCRIBE") => match args.next() {
+            Some(topic) => {
+                // PUBLISH sends the rest of the line, empty messages are rejected
+                swarm.register_topic_validator(&Topic::new(topic.into()), |_: &PeerId, message: &GossipsubMessage| {
+                    !message.data.is_empty()
+                });
+                if swarm.gossipsub.subscribe(Topic::new(topic.into())) {
+                    println!("Subscribed to {}", topic);
+                } else {
+                    println!("Already subscribed to {}", topic);
+                }
+            }
+            None => println!("Expected topic"),
+        },
+        Some("UNSUBSCRIBE") => match args.next() {
+            Some(topic) => {
+                if swarm.gossipsub.unsubscribe(Topic::new(topic.into())) {
+                    println!("Unsubscribed from {}", topic);
+                } else {
+                    println!("Not subscribed to {}", topic);
+                }
+            }
+            None => println!("Expected topic"),
+        },
+        Some("PUBLISH") => match args.next() {
+            Some(topic) => {
+                let message = args.collect::<Vec<&str>>().join(" ");
+                match swarm.gossipsub.publish(&Topic::new(topic.into()), message.into_bytes()) {
+                    Ok(()) => println!("Published message to {}", topic),
+                    Err(e) => eprintln!("Could not publish message to {}: {:?}", topic, e),
+                }
+            }
+            None => println!("Expected topic"),
+        },
+        Some("FORWARD") => match (args.next().map(PeerId::from_str), args.next().map(PeerId::from_str)) {
+            (Some(Ok(forwarder)), Some(Ok(target))) => {
+                println!("Requests to {:?} are forwarded by {:?}", target, forwarder);
+                swarm.add_forward_route(target, forwarder);
+            }
+            (Some(Ok(_)), None) => println!("Expected target peer id"),
+            (None, _) => println!("Expected forwarding peer id"),
+            _ => println!("Faulty peer id"),
+        },
+        Some("DIRECT") => match args.next().map(PeerId::from_str) {
+            Some(Ok(target)) => match swarm.remove_forward_route(&target) {
+                Some(_) => println!("Requests to {:?} are sent directly", target),
+                None => println!("No forward route for {:?}", target),
+            },
+            Some(Err(_)) => println!("Faulty target peer id"),
+            None => println!("Expected target peer id"),
+        },
+        Some("BAN") => match args.next().map(PeerId::from_str) {
+            Some(Ok(peer)) => match args.next().map(u64::from_str) {
+                Some(Ok(secs)) => {
+                    println!("Banning peer {:?} for {} seconds", peer, secs);
+                    swarm.ban_peer(peer, Some(Duration::from_secs(secs)));
+                }
+                Some(Err(_)) => println!("Invalid duration"),
+                None => {
+                    println!("Banning peer {:?}", peer);
+                    swarm.ban_peer(peer, None);
+                }
+            },
+            Some(Err(_)) => println!("Faulty target peer id"),
+            None => println!("Expected target peer id"),
+        },
+        Some("UNBAN") => match args.next().map(PeerId::from_str) {
+            Some(Ok(peer)) => {
+                if swarm.unban_peer(&peer) {
+                    println!("Unbanned peer {:?}", peer);
+                } else {
+                    println!("Peer {:?} is not banned", peer);
+                }
+            }
+            Some(Err(_)) => println!("Faulty target peer id"),
+            None => println!("Expected target peer id"),
+        },
+        Some("BANS") => {
+            println!("Banned Peers:");
+            for (peer, ban) in swarm.bans() {
+                println!(
+                    "peer: {:?}, score: {}, banned for: {:?}, remaining: {:?}",
+                    peer,
+                    swarm.peer_score(peer),
+                    ban.since.elapsed(),
+                    ban.until.map(|until| until.saturating_duration_since(Instant::now()))
+                );
+            }
+        }
+        Some("STATS") => {
+            let (inbound, outbound) = swarm.connections.counts();
+            let limits = swarm.connections.limits();
+            println!(
+                "Connections: {} inbound (max {:?}), {} outbound (max {:?})",
+                inbound, limits.max_inbound, outbound, limits.max_outbound
+            );
+            println!("Peer Stats:");
+            for (peer, stats) in swarm.peer_stats() {
+                println!(
+                    "peer: {:?}, last rtt: {:?}, average rtt: {:?}, failures: {} ({} in a row), last success: {:?} ago",
+                    peer,
+                    stats.last_rtt(),
+                    stats.average_rtt(),
+                    stats.total_failures,
+                    stats.consecutive_failures,
+                    stats.last_success.map(|t| t.elapsed())
+                );
+            }
+        }
+        Some("PENDING") => {
+            println!("Pending Requests:");
+            for (_, pending) in swarm.pending_requests() {
+                println!(
+                    "request: {:?}, command: {}, peer: {:?}, waiting for: {:?}",
+                    pending.id,
+                    pending.command,
+                    pending.peer,
+                    pending.sent_at.elapsed()
+                );
+            }
+        }
         Some("LIST") => {
             println!("Current Buckets:");
             for bucket in swarm.kademlia.kbuckets() {
@@ -199,12 +769,107 @@ fn handle_input_line(swarm: &mut P2PNetworkSwarm, line: String) {
     }
 }
 
-fn send_ping_to_peer(mut args: SplitWhitespace, msg_proto: &mut RequestResponse<CommandCodec>) {
+// Quorum for DHT queries, defaults to one
+fn parse_quorum(quorum: Option<&str>) -> Option<Quorum> {
+    match quorum {
+        None | Some("one") => Some(Quorum::One),
+        Some("majority") => Some(Quorum::Majority),
+        Some("all") => Some(Quorum::All),
+        Some(n) => n.parse().ok().and_then(NonZeroUsize::new).map(Quorum::N),
+    }
+}
+
+fn put_record(mut args: SplitWhitespace, kademlia: &mut Kademlia<NodeStore>) {
+    let (key, value) = match (args.next(), args.next()) {
+        (Some(key), Some(value)) => (Key::new(&key), value.as_bytes().to_vec()),
+        _ => {
+            println!("Expected key and value");
+            return;
+        }
+    };
+    let quorum = match parse_quorum(args.next()) {
+        Some(quorum) => quorum,
+        None => {
+            println!("Invalid quorum");
+            return;
+        }
+    };
+    let record = Record {
+        key,
+        value,
+        publisher: None,
+        expires: None,
+    };
+    match kademlia.put_record(record, quorum) {
+        Ok(query_id) => println!("Storing record with query {:?}", query_id),
+        Err(e) => eprintln!("Failed to store record locally: {:?}", e),
+    }
+}
+
+fn get_record(mut args: SplitWhitespace, kademlia: &mut Kademlia<NodeStore>) {
+    let key = match args.next() {
+        Some(key) => Key::new(&key),
+        None => {
+            println!("Expected key");
+            return;
+        }
+    };
+    match parse_quorum(args.next()) {
+        Some(quorum) => {
+            let query_id = kademlia.get_record(&key, quorum);
+            println!("Getting record with query {:?}", query_id);
+        }
+        None => println!("Invalid quorum"),
+    }
+}
+
+fn send_ping_to_peer(mut args: SplitWhitespace, behaviour: &mut P2PNetworkBehaviour) {
     if let Some(peer_id) = args.next() {
         if let Ok(peer) = PeerId::from_str(peer_id) {
             let ping = CommandRequest::Ping;
             println!("Sending Ping to peer {:?}", peer);
-            msg_proto.send_request(&peer, ping);
+            let sent_at = Instant::now();
+            let (request_id, response) = behaviour.send_request_with_receiver(&peer, ping);
+            task::spawn(async move {
+                match response.await {
+                    Ok(Ok(_)) => println!("Received Pong for request {:?} after {:?}", request_id, sent_at.elapsed()),
+                    Ok(Err(error)) => eprintln!("Ping request {:?} failed: {:?}", request_id, error),
+                    // the node stopped before the request finished
+                    Err(_) => {}
+                }
+            });
+        } else {
+            println!("Faulty target peer id");
+        }
+    } else {
+        println!("Expected target peer id");
+    }
+}
+
+fn send_kv_cmd_to_peer(cmd: &str, mut args: SplitWhitespace, behaviour: &mut P2PNetworkBehaviour) {
+    if let Some(peer_id) = args.next() {
+        if let Ok(peer) = PeerId::from_str(peer_id) {
+            let key = args.next();
+            let request = match (cmd, key) {
+                ("KVLIST", prefix) => CommandRequest::List {
+                    prefix: prefix.unwrap_or_default().to_string(),
+                },
+                (_, None) => {
+                    println!("Expected key");
+                    return;
+                }
+                ("KVGET", Some(key)) => CommandRequest::Get { key: key.to_string() },
+                ("KVDEL", Some(key)) => CommandRequest::Delete { key: key.to_string() },
+                (_, Some(key)) => {
+                    let value = args.collect::<Vec<&str>>().join(" ");
+                    CommandRequest::Put {
+                        key: key.to_string(),
+                        value: value.into_bytes(),
+                    }
+                }
+            };
+            println!("Sending {:?} to peer {:?}", request, peer);
+            behaviour.send_request(&peer, request);
         } else {
             println!("Faulty target peer id");
         }
@@ -213,12 +878,12 @@ fn send_ping_to_peer(mut args: SplitWhitespace, msg_proto: &mut RequestResponse<
     }
 }
 
-fn send_trustping_to_peer(mut args: SplitWhitespace, msg_proto: &mut RequestResponse<CommandCodec>) {
+fn send_trustping_to_peer(mut args: SplitWhitespace, behaviour: &mut P2PNetworkBehaviour) {
     if let Some(peer_id) = args.next() {
         if let Ok(peer) = PeerId::from_str(peer_id) {
             let ping = CommandRequest::Ping;
             println!("Sending Ping to peer {:?}", peer);
-            msg_proto.send_request(&peer, ping);
+            behaviour.send_request(&peer, ping);
         } else {
             println!("Faulty target peer id");
         }
@@ -227,7 +892,7 @@ fn send_trustping_to_peer(mut args: SplitWhitespace, msg_proto: &mut RequestResp
     }
 }
 
-fn send_cmd_to_peer(mut args: SplitWhitespace, msg_proto: &mut RequestResponse<CommandCodec>) {
+fn send_cmd_to_peer(mut args: SplitWhitespace, behaviour: &mut P2PNetworkBehaviour) {
     if let Some(peer_id) = args.next() {
         if let Ok(peer) = PeerId::from_str(peer_id) {
             let cmd = {
@@ -291,12 +956,12 @@ fn send_cmd_to_peer(mut args: SplitWhitespace, msg_proto: &mut RequestResponse<C
 
 
                     // send request for did_comm_message 
-                    msg_proto.send_request(&peer, did_comm_message_request);
+                    behaviour.send_request(&peer, did_comm_message_request);
                     
                 },
                 _ => {
                     println!("send default command");
-                    msg_proto.send_request(&peer, other);
+                    behaviour.send_request(&peer, other);
                 }
             }
 
@@ -4,43 +4,392 @@
 // RequestResponse Protocol with generic Request / Responde messages for custom behaviour
 
 
+use crate::bootstrap::BootstrapSchedule;
+use crate::command_handler::{CommandHandler, CommandHandlerRegistry, HandlerResponse};
 use crate::command_protocol::{
-    CommandCodec,
-    CommandRequest::{self, Other as OtherReq, Ping},
-    CommandResponse::{self, Other as OtherRes, Pong},
+    error_code, header, CommandCodec, RejectedMessage,
+    CommandRequest::{self, Delete, Forward, Get, List, Other as OtherReq, Ping, Put},
+    CommandResponse::{self, Deleted, Error as ErrorRes, Keys, Pong, Stored, Value},
+    Envelope, Headers,
+};
+use crate::connection_limits::{ConnectionLimits, ConnectionTracker};
+use crate::ip_network::IpNetwork;
+use crate::kv_store::{KvStore, StoreError};
+use crate::node_event::NodeEvent;
+use crate::node_store::NodeStore;
+use crate::peer_score::{Ban, BanChange, BanList, Misbehaviour};
+use crate::peer_stats::PeerStats;
+use crate::rate_limit::RateLimiter;
+use crate::retry::RetryPolicy;
+use futures::{
+    channel::{mpsc, oneshot},
+    future::BoxFuture,
+    prelude::*,
+    stream::FuturesUnordered,
 };
 use libp2p::{
-    kad::{store::MemoryStore, Kademlia, KademliaEvent},
+    core::{ConnectedPoint, Multiaddr},
+    gossipsub::{Gossipsub, GossipsubEvent, GossipsubMessage, Topic, TopicHash},
+    identify::{Identify, IdentifyEvent, IdentifyInfo},
+    kad::{
+        record::{
+            store::{self, RecordStore},
+            Key,
+        },
+        AddProviderOk, BootstrapError, BootstrapOk, GetProvidersError, GetProvidersOk, GetRecordOk, Kademlia,
+        KademliaEvent, PeerRecord, PutRecordOk, QueryId, QueryResult,
+    },
     mdns::{Mdns, MdnsEvent},
+    ping::{self, PingEvent, PingSuccess},
     request_response::{
-        RequestId, RequestResponse,
+        self, RequestId, RequestResponse,
         RequestResponseEvent::{self, InboundFailure, Message, OutboundFailure},
         RequestResponseMessage::{Request, Response},
         ResponseChannel,
     },
-    swarm::NetworkBehaviourEventProcess,
-    NetworkBehaviour,
+    swarm::{toggle::Toggle, NetworkBehaviourAction, NetworkBehaviourEventProcess, PollParameters},
+    NetworkBehaviour, PeerId,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use identity_core::did::{DID};
-use identity_comm::did_comm::TrustPing;
-use identity_comm::types::{TRUSTPING};
-use identity_comm::DIDComm_message;
-use identity_common::Timestamp;
+type PendingResponse = BoxFuture<
+    'static,
+    (
+        ResponseChannel<Envelope<CommandResponse>>,
+        Envelope<CommandResponse>,
+    ),
+>;
+
+// Peers that fail this many consecutive pings are removed from the routing table
+pub const MAX_PING_FAILURES: u32 = 3;
+
+// Protocol name of kademlia, used to check if identified peers support it
+const KADEMLIA_PROTOCOL: &str = "/ipfs/kad/1.0.0";
+
+// Validation hook for gossipsub messages of a topic, invoked with the peer that forwarded the
+// message. Rejected messages are neither printed nor propagated.
+pub trait TopicValidator: Send {
+    fn validate(&mut self, propagation_source: &PeerId, message: &GossipsubMessage) -> bool;
+}
+
+impl<F> TopicValidator for F
+where
+    F: FnMut(&PeerId, &GossipsubMessage) -> bool + Send,
+{
+    fn validate(&mut self, propagation_source: &PeerId, message: &GossipsubMessage) -> bool {
+        self(propagation_source, message)
+    }
+}
+
+// Resolves with the response or the failure of a request
+pub type ResponseReceiver =
+    oneshot::Receiver<Result<Envelope<CommandResponse>, request_response::OutboundFailure>>;
+
+type ResponseSender =
+    oneshot::Sender<Result<Envelope<CommandResponse>, request_response::OutboundFailure>>;
+
+// Resolves with the providers of a key that were found by a get_providers query
+pub type ProvidersReceiver = oneshot::Receiver<Result<Vec<PeerId>, GetProvidersError>>;
+
+// Timeouts of outbound requests by command type, commands without own timeout use the default.
+#[derive(Debug, Clone)]
+pub struct RequestTimeouts {
+    pub default: Duration,
+    pub commands: HashMap<String, Duration>,
+}
+
+impl RequestTimeouts {
+    pub fn get(&self, command: &str) -> Duration {
+        self.commands.get(command).copied().unwrap_or(self.default)
+    }
+
+    // Timeout of a FORWARD request for the command. Without own FORWARD timeout it is twice the
+    // timeout of the command, so that the forwarding peer can report that the target timed out.
+    pub fn forward(&self, command: &str) -> Duration {
+        match self.commands.get("FORWARD") {
+            Some(timeout) => *timeout,
+            None => self.get(command) * 2,
+        }
+    }
+
+    // Longest timeout of all commands, including forwarded ones.
+    pub fn max(&self) -> Duration {
+        let max = self.commands.values().copied().fold(self.default, Duration::max);
+        if self.commands.contains_key("FORWARD") {
+            max
+        } else {
+            max * 2
+        }
+    }
+}
+
+impl Default for RequestTimeouts {
+    // 10 seconds for all commands, the default request timeout of libp2p request-response
+    fn default() -> Self {
+        RequestTimeouts {
+            default: Duration::from_secs(10),
+            commands: HashMap::new(),
+        }
+    }
+}
+
+// An outbound request that did not receive a response yet
+pub struct PendingRequest {
+    // id that send_request returned, retries keep it although msg_proto assigns them new ids
+    pub id: RequestId,
+    pub peer: PeerId,
+    pub command: &'static str,
+    pub sent_at: Instant,
+    // the request fails with a timeout if there is no response until then
+    pub deadline: Instant,
+    sender: Option<ResponseSender>,
+    // set if the request is retried after a failure
+    retry: Option<RetryState>,
+}
+
+// The sent request and the peer it was sent to, which is the forwarding peer for forwarded
+// requests.
+struct RetryState {
+    id: RequestId,
+    peer: PeerId,
+    request: Envelope<CommandRequest>,
+    attempt: u32,
+}
+
+// A failed request that is sent again once its backoff elapsed, or earlier if the peer connects.
+struct ScheduledRetry {
+    due: Instant,
+    target: PeerId,
+    command: &'static str,
+    retry: RetryState,
+    sender: Option<ResponseSender>,
+    // failure of the last attempt, reported if the retry is cancelled
+    error: request_response::OutboundFailure,
+}
 
 #[derive(NetworkBehaviour)]
+#[behaviour(poll_method = "poll")]
 pub struct P2PNetworkBehaviour {
-    pub(crate) kademlia: Kademlia<MemoryStore>,
-    pub(crate) mdns: Mdns,
+    pub(crate) kademlia: Kademlia<NodeStore>,
+    pub(crate) mdns: Toggle<Mdns>,
     pub(crate) msg_proto: RequestResponse<CommandCodec>,
+    pub(crate) identify: Identify,
+    pub(crate) ping: ping::Ping,
+    pub(crate) gossipsub: Gossipsub,
+    #[behaviour(ignore)]
+    handlers: CommandHandlerRegistry,
+    // data that remote peers access with key-value commands
+    #[behaviour(ignore)]
+    pub(crate) store: KvStore,
+    // peers that are allowed to put and delete values, None allows all peers that are not banned
+    #[behaviour(ignore)]
+    pub(crate) store_writers: Option<HashSet<PeerId>>,
+    // responses of asynchronous handlers that are sent once they are ready
+    #[behaviour(ignore)]
+    pending_responses: FuturesUnordered<PendingResponse>,
+    // sent in the sender-app-id header of all messages
+    #[behaviour(ignore)]
+    pub(crate) app_id: Option<String>,
+    // sent requests by their id, until the response or a failure is received
+    #[behaviour(ignore)]
+    pending_requests: HashMap<RequestId, PendingRequest>,
+    // info that connected peers sent via the identify protocol
+    #[behaviour(ignore)]
+    peer_info: HashMap<PeerId, IdentifyInfo>,
+    // round-trip times and failures of the ping protocol, of connected peers
+    #[behaviour(ignore)]
+    peer_stats: HashMap<PeerId, PeerStats>,
+    // messages of a topic are only propagated if its validator accepts them
+    #[behaviour(ignore)]
+    topic_validators: HashMap<TopicHash, Box<dyn TopicValidator>>,
+    // get_providers queries whose result is sent to a receiver instead of being emitted
+    #[behaviour(ignore)]
+    provider_queries: HashMap<QueryId, oneshot::Sender<Result<Vec<PeerId>, GetProvidersError>>>,
+    // peers whose FORWARD requests are forwarded to their target, forwarding is disabled if it
+    // is empty
+    #[behaviour(ignore)]
+    pub(crate) forward_for: HashSet<PeerId>,
+    // peers that are not reachable directly, mapped to the peer that forwards requests to them
+    #[behaviour(ignore)]
+    forward_routes: HashMap<PeerId, PeerId>,
+    // misbehaviour scores and banned peers, changes of the bans are applied by the swarm
+    #[behaviour(ignore)]
+    ban_list: BanList,
+    // wakes up the behaviour when the next temporary ban expires
+    #[behaviour(ignore)]
+    ban_timer: Option<(Instant, BoxFuture<'static, ()>)>,
+    // established connections, that are limited per direction
+    #[behaviour(ignore)]
+    pub(crate) connections: ConnectionTracker,
+    // filter of the addresses discovered via mDNS: they are only added if they are in one of the
+    // networks, all addresses are accepted if it is empty. mDNS itself still runs on all
+    // interfaces, the filter does not bind it to specific ones.
+    #[behaviour(ignore)]
+    pub(crate) mdns_filter: Vec<IpNetwork>,
+    // periodic bootstrap to refresh the routing table and to rejoin the network
+    #[behaviour(ignore)]
+    pub(crate) bootstrap: BootstrapSchedule,
+    // limits the inbound requests per peer and command type, None disables the limit
+    #[behaviour(ignore)]
+    pub(crate) rate_limiter: Option<RateLimiter>,
+    // the timeout of msg_proto has to be at least the longest of these timeouts
+    #[behaviour(ignore)]
+    pub(crate) request_timeouts: RequestTimeouts,
+    // wakes up the behaviour at the earliest deadline of the pending requests
+    #[behaviour(ignore)]
+    timeout_timer: Option<(Instant, BoxFuture<'static, ()>)>,
+    // requests that timed out before msg_proto reported their response or failure, which is
+    // dropped when it arrives
+    #[behaviour(ignore)]
+    timed_out_requests: HashSet<RequestId>,
+    // failed requests are retried if a policy is set
+    #[behaviour(ignore)]
+    pub(crate) retry_policy: Option<RetryPolicy>,
+    #[behaviour(ignore)]
+    scheduled_retries: Vec<ScheduledRetry>,
+    // wakes up the behaviour when the next scheduled retry is due
+    #[behaviour(ignore)]
+    retry_timer: Option<(Instant, BoxFuture<'static, ()>)>,
+    // messages that the codec rejected, they are reported as events
+    #[behaviour(ignore)]
+    pub(crate) rejected_messages: Option<mpsc::Receiver<RejectedMessage>>,
+    // subscribers of the network events, events are dropped if nobody subscribed
+    #[behaviour(ignore)]
+    subscribers: Vec<EventSubscriber>,
+}
+
+// Number of events that are buffered for a subscriber that does not keep up.
+const EVENT_BUFFER_SIZE: usize = 1024;
+
+struct EventSubscriber {
+    sender: mpsc::Sender<Arc<NodeEvent>>,
+    // events that were dropped because the buffer of the subscriber was full
+    dropped: u64,
 }
 
 impl NetworkBehaviourEventProcess<MdnsEvent> for P2PNetworkBehaviour {
     // Called when `mdns` produces an event.
     fn inject_event(&mut self, event: MdnsEvent) {
-        if let MdnsEvent::Discovered(list) = event {
-            for (peer_id, multiaddr) in list {
-                self.kademlia.add_address(&peer_id, multiaddr);
+        match event {
+            MdnsEvent::Discovered(list) => {
+                for (peer_id, multiaddr) in list {
+                    if self.is_mdns_addr_allowed(&multiaddr) {
+                        self.kademlia.add_address(&peer_id, multiaddr.clone());
+                        self.emit(NodeEvent::PeerDiscovered {
+                            peer: peer_id,
+                            addr: multiaddr,
+                        });
+                    }
+                }
+            }
+            MdnsEvent::Expired(list) => {
+                for (peer_id, multiaddr) in list {
+                    if self.kademlia.remove_address(&peer_id, &multiaddr).is_some() {
+                        self.emit(NodeEvent::PeerExpired {
+                            peer: peer_id,
+                            addr: multiaddr,
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<IdentifyEvent> for P2PNetworkBehaviour {
+    // Called when `identify` produces an event.
+    fn inject_event(&mut self, event: IdentifyEvent) {
+        match event {
+            IdentifyEvent::Received {
+                peer_id,
+                info,
+                observed_addr,
+            } => {
+                self.emit(NodeEvent::PeerIdentified {
+                    peer: peer_id.clone(),
+                    agent_version: info.agent_version.clone(),
+                    observed_addr,
+                });
+                // only peers that speak kademlia are added to the routing table
+                if info.protocols.iter().any(|p| p == KADEMLIA_PROTOCOL) {
+                    for addr in &info.listen_addrs {
+                        self.kademlia.add_address(&peer_id, addr.clone());
+                    }
+                }
+                self.peer_info.insert(peer_id, info);
+            }
+            IdentifyEvent::Sent { .. } => {}
+            IdentifyEvent::Error { peer_id, error } => {
+                self.emit(NodeEvent::IdentifyFailed { peer: peer_id, error });
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<PingEvent> for P2PNetworkBehaviour {
+    // Called when `ping` produces an event.
+    fn inject_event(&mut self, event: PingEvent) {
+        let stats = self.peer_stats.entry(event.peer.clone()).or_default();
+        match event.result {
+            Ok(PingSuccess::Ping { rtt }) => stats.record_success(rtt),
+            Ok(PingSuccess::Pong) => {}
+            Err(error) => {
+                let failures = stats.record_failure();
+                self.emit(NodeEvent::PingFailed {
+                    peer: event.peer.clone(),
+                    consecutive_failures: failures,
+                    error,
+                });
+                if failures == MAX_PING_FAILURES {
+                    self.kademlia.remove_peer(&event.peer);
+                    self.emit(NodeEvent::PeerUnresponsive { peer: event.peer });
+                    if self.routing_table_size() == 0 {
+                        self.emit(NodeEvent::AllPeersLost);
+                        self.bootstrap.schedule(Duration::from_secs(0));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<GossipsubEvent> for P2PNetworkBehaviour {
+    // Called when `gossipsub` produces an event.
+    fn inject_event(&mut self, event: GossipsubEvent) {
+        match event {
+            GossipsubEvent::Message(propagation_source, id, message) => {
+                let validators = &mut self.topic_validators;
+                let is_valid = message.topics.iter().all(|topic| match validators.get_mut(topic) {
+                    Some(validator) => validator.validate(&propagation_source, &message),
+                    None => true,
+                });
+                if !is_valid {
+                    self.ban_list.penalise(&propagation_source, Misbehaviour::MalformedMessage);
+                    self.emit(NodeEvent::GossipMessageRejected {
+                        propagation_source,
+                        id,
+                    });
+                    return;
+                }
+                // gossipsub is configured to only forward messages after they were validated
+                self.gossipsub.validate_message(&id, &propagation_source);
+                self.emit(NodeEvent::GossipMessage {
+                    propagation_source,
+                    id,
+                    message,
+                });
+            }
+            GossipsubEvent::Subscribed { peer_id, topic } => {
+                self.emit(NodeEvent::TopicSubscribed { peer: peer_id, topic });
+            }
+            GossipsubEvent::Unsubscribed { peer_id, topic } => {
+                self.emit(NodeEvent::TopicUnsubscribed { peer: peer_id, topic });
             }
         }
     }
@@ -48,42 +397,107 @@ impl NetworkBehaviourEventProcess<MdnsEvent> for P2PNetworkBehaviour {
 
 impl NetworkBehaviourEventProcess<KademliaEvent> for P2PNetworkBehaviour {
     // Called when `kademlia` produces an event.
-    fn inject_event(&mut self, _message: KademliaEvent) {}
+    fn inject_event(&mut self, message: KademliaEvent) {
+        if let KademliaEvent::QueryResult { id, result, .. } = message {
+            match result {
+                QueryResult::Bootstrap(result) => {
+                    let is_finished = match &result {
+                        Ok(BootstrapOk { num_remaining, .. }) => *num_remaining == 0,
+                        Err(BootstrapError::Timeout { num_remaining, .. }) => {
+                            num_remaining.map_or(true, |remaining| remaining == 0)
+                        }
+                    };
+                    self.emit(NodeEvent::BootstrapProgress { query: id, result });
+                    // only the scheduled bootstrap is retried or refreshed
+                    if is_finished && self.bootstrap.query() == Some(id) {
+                        self.bootstrap_finished();
+                    }
+                }
+                QueryResult::GetRecord(Ok(GetRecordOk { records })) => {
+                    for PeerRecord { peer, record } in records {
+                        self.emit(NodeEvent::RecordFound { query: id, peer, record });
+                    }
+                }
+                QueryResult::GetRecord(Err(error)) => {
+                    self.emit(NodeEvent::GetRecordFailed { query: id, error });
+                }
+                QueryResult::GetProviders(Ok(GetProvidersOk { key, providers, .. })) => {
+                    let providers = providers.into_iter().collect::<Vec<_>>();
+                    if let Some(sender) = self.provider_queries.remove(&id) {
+                        let _ = sender.send(Ok(providers));
+                    } else {
+                        self.emit(NodeEvent::ProvidersFound {
+                            query: id,
+                            key,
+                            providers,
+                        });
+                    }
+                }
+                QueryResult::GetProviders(Err(error)) => {
+                    if let Some(sender) = self.provider_queries.remove(&id) {
+                        let _ = sender.send(Err(error));
+                    } else {
+                        self.emit(NodeEvent::GetProvidersFailed { query: id, error });
+                    }
+                }
+                QueryResult::StartProviding(Ok(AddProviderOk { key })) => {
+                    self.emit(NodeEvent::ProvidingStarted { query: id, key });
+                }
+                QueryResult::StartProviding(Err(error)) => {
+                    self.emit(NodeEvent::StartProvidingFailed { query: id, error });
+                }
+                QueryResult::RepublishProvider(Ok(AddProviderOk { key })) => {
+                    self.emit(NodeEvent::ProviderRepublished { key });
+                }
+                QueryResult::RepublishProvider(Err(error)) => {
+                    self.emit(NodeEvent::RepublishProviderFailed { error });
+                }
+                QueryResult::PutRecord(Ok(PutRecordOk { key })) => {
+                    self.emit(NodeEvent::RecordStored { query: id, key });
+                }
+                QueryResult::PutRecord(Err(error)) => {
+                    self.emit(NodeEvent::PutRecordFailed { query: id, error });
+                }
+                _ => {}
+            }
+        }
+    }
 }
 
-impl NetworkBehaviourEventProcess<RequestResponseEvent<CommandRequest, CommandResponse>>
+impl NetworkBehaviourEventProcess<RequestResponseEvent<Envelope<CommandRequest>, Envelope<CommandResponse>>>
     for P2PNetworkBehaviour
 {
     // Called when the command_protocol produces an event.
-    fn inject_event(&mut self, event: RequestResponseEvent<CommandRequest, CommandResponse>) {
+    fn inject_event(&mut self, event: RequestResponseEvent<Envelope<CommandRequest>, Envelope<CommandResponse>>) {
         match event {
-            Message { peer: _, message } => match message {
+            Message { peer, message } => match message {
                 Request {
                     request_id: _,
                     request,
                     channel,
-                } => self.handle_request_msg(request, channel),
+                } => self.handle_request_msg(peer, request, channel),
                 Response {
                     request_id,
                     response,
-                } => self.handle_response_msg(request_id, response),
+                } => self.handle_response_msg(peer, request_id, response),
             },
             OutboundFailure {
                 peer,
                 request_id,
                 error,
-            } => println!(
-                "Outbound Failure for request {:?} to peer: {:?}: {:?}",
-                request_id, peer, error
-            ),
+            } => self.handle_outbound_failure(peer, request_id, error),
+            // inbound failures are not penalised: timeouts, closed connections and unsupported
+            // protocols are not necessarily caused by the remote peer, malformed requests are
+            // penalised when they are handled
             InboundFailure {
                 peer,
                 request_id,
                 error,
-            } => println!(
-                "Inbound Failure for request {:?} to peer: {:?}: {:?}",
-                request_id, peer, error
-            ),
+            } => self.emit(NodeEvent::InboundFailure {
+                peer,
+                request_id,
+                error,
+            }),
         }
     }
 }
@@ -91,87 +505,952 @@ impl NetworkBehaviourEventProcess<RequestResponseEvent<CommandRequest, CommandRe
 
 
 impl P2PNetworkBehaviour {
-    fn handle_request_msg(
+    pub fn new(
+        kademlia: Kademlia<NodeStore>,
+        mdns: Toggle<Mdns>,
+        msg_proto: RequestResponse<CommandCodec>,
+        identify: Identify,
+        ping: ping::Ping,
+        gossipsub: Gossipsub,
+    ) -> Self {
+        P2PNetworkBehaviour {
+            kademlia,
+            mdns,
+            msg_proto,
+            identify,
+            ping,
+            gossipsub,
+            handlers: CommandHandlerRegistry::default(),
+            store: KvStore::default(),
+            store_writers: None,
+            pending_responses: FuturesUnordered::new(),
+            app_id: None,
+            pending_requests: HashMap::new(),
+            peer_info: HashMap::new(),
+            peer_stats: HashMap::new(),
+            topic_validators: HashMap::new(),
+            provider_queries: HashMap::new(),
+            forward_for: HashSet::new(),
+            forward_routes: HashMap::new(),
+            ban_list: BanList::default(),
+            ban_timer: None,
+            connections: ConnectionTracker::new(ConnectionLimits::default()),
+            mdns_filter: Vec::new(),
+            bootstrap: BootstrapSchedule::new(None),
+            rate_limiter: Some(RateLimiter::default()),
+            request_timeouts: RequestTimeouts::default(),
+            timeout_timer: None,
+            timed_out_requests: HashSet::new(),
+            retry_policy: None,
+            scheduled_retries: Vec::new(),
+            retry_timer: None,
+            rejected_messages: None,
+            subscribers: Vec::new(),
+        }
+    }
+
+    // Subscribe to the network events of the node. Every subscriber receives all events, events
+    // are dropped for a subscriber whose buffer is full and it is told how many it missed.
+    pub fn event_stream(&mut self) -> mpsc::Receiver<Arc<NodeEvent>> {
+        let (sender, receiver) = mpsc::channel(EVENT_BUFFER_SIZE);
+        self.subscribers.push(EventSubscriber { sender, dropped: 0 });
+        receiver
+    }
+
+    pub fn emit(&mut self, event: NodeEvent) {
+        let event = Arc::new(event);
+        self.subscribers.retain(|subscriber| !subscriber.sender.is_closed());
+        for subscriber in &mut self.subscribers {
+            if subscriber.dropped > 0 {
+                let lagged = Arc::new(NodeEvent::EventsDropped {
+                    count: subscriber.dropped,
+                });
+                if subscriber.sender.try_send(lagged).is_err() {
+                    subscriber.dropped += 1;
+                    continue;
+                }
+                subscriber.dropped = 0;
+            }
+            if subscriber.sender.try_send(event.clone()).is_err() {
+                subscriber.dropped += 1;
+            }
+        }
+    }
+
+    // Register a handler for CommandRequest::Other messages with the given content-type, command
+    // name or DIDComm message type
+    pub fn register_handler<H>(&mut self, key: impl Into<String>, handler: H)
+    where
+        H: CommandHandler + 'static,
+    {
+        self.handlers.register(key, handler);
+    }
+
+    // Remove the handler for the key, returns false if no handler was registered.
+    pub fn unregister_handler(&mut self, key: &str) -> bool {
+        self.handlers.unregister(key).is_some()
+    }
+
+    // Wrap the request in an envelope with the default headers and send it to the peer.
+    pub fn send_request(&mut self, peer: &PeerId, request: CommandRequest) -> RequestId {
+        let request = Envelope::new(request).with_header(header::TRACE_ID, format!("{:016x}", rand::random::<u64>()));
+        self.send_request_with_headers(peer, request.body, request.headers)
+    }
+
+    // Send the request and receive its response or failure through the returned receiver instead
+    // of emitting it.
+    pub fn send_request_with_receiver(&mut self, peer: &PeerId, request: CommandRequest) -> (RequestId, ResponseReceiver) {
+        let (sender, receiver) = oneshot::channel();
+        let request_id = self.send_request(peer, request);
+        if let Some(pending) = self.pending_requests.get_mut(&request_id) {
+            pending.sender = Some(sender);
+        }
+        (request_id, receiver)
+    }
+
+    // Send the request with the given headers, the timestamp and sender-app-id headers are added
+    // if they are missing. Requests to peers with a forward route are sent to the forwarding peer
+    // instead.
+    pub fn send_request_with_headers(
         &mut self,
+        peer: &PeerId,
         request: CommandRequest,
-        channel: ResponseChannel<CommandResponse>,
-    ) {
+        mut headers: Headers,
+    ) -> RequestId {
+        self.add_default_headers(&mut headers);
+        let request = Envelope { headers, body: request };
+        let command = request.body.name();
+        let (receiver, request) = match self.forward_routes.get(peer).cloned() {
+            Some(forwarder) => {
+                let forwarded = Envelope {
+                    headers: request.headers.clone(),
+                    body: Forward {
+                        target: peer.to_base58(),
+                        request: Box::new(request),
+                    },
+                };
+                (forwarder, forwarded)
+            }
+            None => (peer.clone(), request),
+        };
+        let retry = self.retry_policy.as_ref().map(|_| (receiver.clone(), request.clone()));
+        let request_id = self.send_tracked(peer, &receiver, command, request, None);
+        if let Some(pending) = self.pending_requests.get_mut(&request_id) {
+            pending.retry = retry.map(|(peer, request)| RetryState {
+                id: request_id,
+                peer,
+                request,
+                attempt: 1,
+            });
+        }
+        request_id
+    }
+
+    // Send the request to the peer and remember it until the response or a failure is received,
+    // target is the peer that actually handles the request.
+    fn send_tracked(
+        &mut self,
+        target: &PeerId,
+        peer: &PeerId,
+        command: &'static str,
+        request: Envelope<CommandRequest>,
+        sender: Option<ResponseSender>,
+    ) -> RequestId {
+        let sent_at = Instant::now();
+        let deadline = sent_at + self.request_timeout(&request.body);
+        let request_id = self.msg_proto.send_request(peer, request);
+        let pending = PendingRequest {
+            id: request_id,
+            peer: target.clone(),
+            command,
+            sent_at,
+            deadline,
+            sender,
+            retry: None,
+        };
+        self.pending_requests.insert(request_id, pending);
+        request_id
+    }
+
+    // Ban the peer for the duration, or until it is unbanned if no duration is given. Its
+    // connections are closed and it is removed from the routing table.
+    pub fn ban_peer(&mut self, peer: PeerId, duration: Option<Duration>) {
+        self.kademlia.remove_peer(&peer);
+        self.ban_list.ban(peer, duration);
+        self.cancel_banned_retries();
+    }
+
+    // Returns false if the peer was not banned.
+    pub fn unban_peer(&mut self, peer: &PeerId) -> bool {
+        self.ban_list.unban(peer)
+    }
+
+    pub fn bans(&self) -> impl Iterator<Item = (&PeerId, &Ban)> {
+        self.ban_list.bans()
+    }
+
+    // Misbehaviour score of the peer, 0 for peers that did not misbehave.
+    pub fn peer_score(&self, peer: &PeerId) -> i32 {
+        self.ban_list.score(peer)
+    }
+
+    pub fn is_banned(&self, peer: &PeerId) -> bool {
+        self.ban_list.is_banned(peer)
+    }
+
+    // Next ban or unban that has to be applied to the swarm, so that connections of banned peers
+    // are refused.
+    pub fn next_ban_change(&mut self) -> Option<BanChange> {
+        self.ban_list.next_change()
+    }
+
+    // Remember the bootstrap peer, so that the node can rejoin the network through it, and
+    // bootstrap immediately.
+    pub fn add_bootstrap_peer(&mut self, peer: PeerId, addr: Multiaddr) {
+        self.kademlia.add_address(&peer, addr.clone());
+        self.bootstrap.add_peer(peer, addr);
+        self.bootstrap.schedule(Duration::from_secs(0));
+    }
+
+    // Number of peers in the kademlia routing table.
+    pub fn routing_table_size(&mut self) -> usize {
+        self.kademlia.kbuckets().map(|bucket| bucket.num_entries()).sum()
+    }
+
+    fn start_bootstrap(&mut self) {
+        // the bootstrap peers are removed from the routing table if all peers were lost
+        if self.routing_table_size() == 0 {
+            for (peer, addr) in self.bootstrap.peers().to_vec() {
+                self.kademlia.add_address(&peer, addr);
+            }
+        }
+        match self.kademlia.bootstrap() {
+            Ok(query_id) => {
+                self.emit(NodeEvent::BootstrapStarted { query: query_id });
+                self.bootstrap.started(query_id);
+            }
+            // kademlia can only fail to bootstrap if it does not know any peers
+            Err(_) => {
+                let retry_in = self.bootstrap.failed();
+                self.emit(NodeEvent::BootstrapFailed { retry_in });
+            }
+        }
+    }
+
+    fn bootstrap_finished(&mut self) {
+        match self.routing_table_size() {
+            0 => {
+                let retry_in = self.bootstrap.failed();
+                self.emit(NodeEvent::BootstrapFoundNoPeers { retry_in });
+            }
+            peers => {
+                self.emit(NodeEvent::BootstrapFinished { peers });
+                self.bootstrap.succeeded();
+            }
+        }
+    }
+
+    // Count a connection that the swarm established, returns the peer that should be disconnected
+    // if a connection limit is exceeded.
+    // Retries of requests to the peer are sent right away.
+    pub fn connection_established(&mut self, peer: &PeerId, endpoint: &ConnectedPoint) -> Option<PeerId> {
+        let now = Instant::now();
+        for scheduled in self.scheduled_retries.iter_mut().filter(|scheduled| scheduled.retry.peer == *peer) {
+            scheduled.due = scheduled.due.min(now);
+        }
+        let ban_list = &self.ban_list;
+        self.connections.on_established(peer, endpoint, |peer| ban_list.score(peer))
+    }
+
+    // Forget the identify info and ping statistics of the peer once its last connection is closed,
+    // they are collected again when the peer reconnects.
+    pub fn connection_closed(&mut self, peer: &PeerId, endpoint: &ConnectedPoint) {
+        self.connections.on_closed(peer, endpoint);
+        if !self.connections.is_connected(peer) {
+            self.peer_info.remove(peer);
+            self.peer_stats.remove(peer);
+        }
+    }
+
+    // Send requests to the target as FORWARD requests to the forwarding peer, e.g. because the
+    // target only accepts connections of the forwarding peer. The forwarding peer has to allow
+    // this node in its forward_for list.
+    pub fn add_forward_route(&mut self, target: PeerId, forwarder: PeerId) {
+        self.forward_routes.insert(target, forwarder);
+    }
+
+    pub fn remove_forward_route(&mut self, target: &PeerId) -> Option<PeerId> {
+        self.forward_routes.remove(target)
+    }
+
+    // Announce to the DHT that this node provides the key, the announcement is periodically
+    // repeated by kademlia until stop_providing is called.
+    pub fn start_providing(&mut self, key: Key) -> Result<QueryId, store::Error> {
+        self.kademlia.start_providing(key)
+    }
+
+    pub fn stop_providing(&mut self, key: &Key) {
+        self.kademlia.stop_providing(key);
+    }
+
+    // Search for the providers of the key, the result is printed.
+    pub fn get_providers(&mut self, key: Key) -> QueryId {
+        self.kademlia.get_providers(key)
+    }
+
+    // Search for the providers of the key and receive the result through the returned receiver.
+    pub fn get_providers_with_receiver(&mut self, key: Key) -> (QueryId, ProvidersReceiver) {
+        let (sender, receiver) = oneshot::channel();
+        let query_id = self.get_providers(key);
+        self.provider_queries.insert(query_id, sender);
+        (query_id, receiver)
+    }
+
+    // Keys that this node announces as provider.
+    pub fn provided_keys(&mut self) -> Vec<Key> {
+        self.kademlia
+            .store_mut()
+            .provided()
+            .map(|record| record.key.clone())
+            .collect()
+    }
+
+    // Info about the remote peer, that was received via the identify protocol.
+    pub fn peer_info(&self, peer: &PeerId) -> Option<&IdentifyInfo> {
+        self.peer_info.get(peer)
+    }
+
+    // Register a validator for the messages of the topic, replaces an existing validator.
+    pub fn register_topic_validator<V>(&mut self, topic: &Topic, validator: V)
+    where
+        V: TopicValidator + 'static,
+    {
+        self.topic_validators.insert(topic.no_hash(), Box::new(validator));
+    }
+
+    // Ping statistics of all peers that were pinged.
+    pub fn peer_stats(&self) -> impl Iterator<Item = (&PeerId, &PeerStats)> {
+        self.peer_stats.iter()
+    }
+
+    // Requests that were sent, but did not receive a response or failure yet.
+    pub fn pending_requests(&self) -> impl Iterator<Item = (&RequestId, &PendingRequest)> {
+        self.pending_requests.iter()
+    }
+
+    // Timeout of an outbound request, requests to a forwarding peer use the FORWARD timeout.
+    fn request_timeout(&self, request: &CommandRequest) -> Duration {
         match request {
-            Ping => {
-                println!("Received Ping, we will send a Pong back");
-                self.msg_proto.send_response(channel, Pong);
-            }
-            OtherReq(cmd) => {
-
-                println!(
-                    "Received: {:?}",
-                    String::from_utf8(cmd.to_owned()).unwrap()
-                );
-
-                let message = String::from_utf8(cmd).unwrap();
-
-                
-                if let Ok(message) = serde_json::from_str::<DIDComm_message>(&message){
-                    println!("message: {:?}", message);
-
-                    match message.type_() {
-                        Some(TRUSTPING) => {
-                          // Send trust pong
-                          println!("Received trustping: {:?}", message);
-                        // let trustping_string = serde_json::to_string(&DIDComm::TrustPing{did}).unwrap();
-                        let did = DID {
-                            method_name: "iota".into(),
-                            id_segments: vec!["pong".into()],
-                            ..Default::default()
-                        }
-                        .init()
-                        .unwrap();
-                        let mut did_comm_message: DIDComm_message = DIDComm_message::new();
-                        did_comm_message.set_id(Timestamp::now().to_rfc3339().to_string());
-                        did_comm_message.set_from(did.to_string());
-                        did_comm_message.set_to(message.from().unwrap());
-                        did_comm_message.set_type(TRUSTPING); 
-
-                        let ping = TrustPing { response_requested: false };                    
-                        let value = serde_json::to_value(ping).unwrap();
-                        let object = value.as_object().unwrap();
-                        did_comm_message.set_body(object.clone());
-                        let did_comm_message_string = serde_json::to_string(&did_comm_message).unwrap();
-                                        
-                        // sign message
-
-
-                        // send signed response
-
-                        self.msg_proto.send_response(
-                                    channel,
-                                    OtherRes(did_comm_message_string.as_bytes().to_vec()))
-                        },
-                        _ => println!("Unkown type")
-                    }
-                } else {
-                        println!("DEFAULT message: we will Send a 'success' back");
-                            self
-                            .msg_proto
-                            .send_response(channel, OtherRes(String::from("Success").into_bytes()));
+            Forward { request, .. } => self.request_timeouts.forward(request.body.name()),
+            request => self.request_timeouts.get(request.name()),
+        }
+    }
+
+    fn is_mdns_addr_allowed(&self, addr: &Multiaddr) -> bool {
+        self.mdns_filter.is_empty() || self.mdns_filter.iter().any(|network| network.contains_addr(addr))
+    }
 
+    fn add_default_headers(&self, headers: &mut Headers) {
+        headers
+            .entry(header::TIMESTAMP.into())
+            .or_insert_with(timestamp_now);
+        if let Some(app_id) = &self.app_id {
+            headers
+                .entry(header::SENDER_APP_ID.into())
+                .or_insert_with(|| app_id.clone());
+        }
+    }
+
+    // Headers of a response, the trace-id is copied from the request.
+    fn response_headers(&self, request_headers: &Headers) -> Headers {
+        let mut headers = Headers::new();
+        if let Some(trace_id) = request_headers.get(header::TRACE_ID) {
+            headers.insert(header::TRACE_ID.into(), trace_id.clone());
+        }
+        self.add_default_headers(&mut headers);
+        headers
+    }
+
+    fn handle_request_msg(
+        &mut self,
+        peer: PeerId,
+        request: Envelope<CommandRequest>,
+        channel: ResponseChannel<Envelope<CommandResponse>>,
+    ) {
+        self.emit(NodeEvent::RequestReceived {
+            peer: peer.clone(),
+            command: request.body.name(),
+            headers: request.headers.clone(),
+        });
+        let headers = self.response_headers(&request.headers);
+        if self.ban_list.is_banned(&peer) {
+            let response = CommandResponse::error(error_code::FORBIDDEN, "peer is banned");
+            self.msg_proto.send_response(channel, Envelope { headers, body: response });
+            return;
+        }
+        let command = request.body.name();
+        if let Some(Err(retry_after)) = self.rate_limiter.as_mut().map(|limiter| limiter.check(&peer, command)) {
+            self.emit(NodeEvent::RequestRateLimited {
+                peer: peer.clone(),
+                command,
+                retry_after,
+            });
+            self.ban_list.penalise(&peer, Misbehaviour::Spam);
+            let response = CommandResponse::error(error_code::TOO_MANY_REQUESTS, "rate limited");
+            let response = Envelope { headers, body: response }
+                .with_header(header::RETRY_AFTER, retry_after.as_millis().to_string());
+            self.msg_proto.send_response(channel, response);
+            return;
+        }
+        let response = match request.body {
+            Ping => Pong,
+            OtherReq(cmd) => match self.handlers.dispatch(&peer, &request.headers, cmd) {
+                HandlerResponse::Ready(response) => response,
+                HandlerResponse::Pending(response) => {
+                    self.pending_responses
+                        .push(response.map(move |body| (channel, Envelope { headers, body })).boxed());
+                    return;
                 }
+            },
+            Get { key } => match self.store.get(&key) {
+                Some(value) => Value(value.clone()),
+                None => CommandResponse::error(error_code::NOT_FOUND, format!("no value for key: {}", key)),
+            },
+            Put { .. } | Delete { .. } if !self.is_store_writer(&peer) => {
+                CommandResponse::error(error_code::FORBIDDEN, "peer is not allowed to write to the store")
             }
+            Put { key, value } => match self.store.put(key, value) {
+                Ok(_) => Stored,
+                Err(e @ StoreError::ValueTooLarge { .. }) => {
+                    CommandResponse::error(error_code::PAYLOAD_TOO_LARGE, e.to_string())
+                }
+                Err(e) => CommandResponse::error(error_code::INSUFFICIENT_STORAGE, e.to_string()),
+            },
+            Delete { key } => match self.store.delete(&key) {
+                Some(_) => Deleted,
+                None => CommandResponse::error(error_code::NOT_FOUND, format!("no value for key: {}", key)),
+            },
+            List { prefix } => Keys(self.store.list(&prefix)),
+            Forward { target, request } => match self.forward_request(&peer, &target, *request) {
+                Ok(response) => {
+                    self.pending_responses
+                        .push(response.map(move |body| (channel, Envelope { headers, body })).boxed());
+                    return;
+                }
+                Err(response) => response,
+            },
+        };
+        if let ErrorRes { code: error_code::BAD_REQUEST, .. } = response {
+            self.ban_list.penalise(&peer, Misbehaviour::MalformedMessage);
+        }
+        self.msg_proto.send_response(channel, Envelope { headers, body: response });
+    }
+
+    fn is_store_writer(&self, peer: &PeerId) -> bool {
+        self.store_writers.as_ref().map_or(true, |writers| writers.contains(peer))
+    }
+
+    // Forward the request of a FORWARD message to its target, the returned future resolves with
+    // the response of the target. Returns the error response if the request can not be forwarded.
+    fn forward_request(
+        &mut self,
+        peer: &PeerId,
+        target: &str,
+        request: Envelope<CommandRequest>,
+    ) -> Result<BoxFuture<'static, CommandResponse>, CommandResponse> {
+        if self.forward_for.is_empty() {
+            return Err(CommandResponse::error(error_code::UNSUPPORTED, "forwarding is disabled"));
         }
+        if !self.forward_for.contains(peer) {
+            return Err(CommandResponse::error(error_code::FORBIDDEN, "peer is not allowed to forward requests"));
+        }
+        let target = PeerId::from_str(target)
+            .map_err(|_| CommandResponse::error(error_code::BAD_REQUEST, format!("invalid target: {}", target)))?;
+        if let Forward { .. } = request.body {
+            return Err(CommandResponse::error(error_code::BAD_REQUEST, "nested forward requests are not supported"));
+        }
+        self.emit(NodeEvent::RequestForwarded {
+            peer: peer.clone(),
+            target: target.clone(),
+            command: request.body.name(),
+        });
+        let (sender, receiver) = oneshot::channel();
+        let command = request.body.name();
+        self.send_tracked(&target, &target, command, request, Some(sender));
+        let response = receiver.map(|result| match result {
+            Ok(Ok(response)) => response.body,
+            Ok(Err(error)) => CommandResponse::error(
+                error_code::BAD_GATEWAY,
+                format!("could not reach target: {:?}", error),
+            ),
+            Err(_) => CommandResponse::error(error_code::BAD_GATEWAY, "forwarded request was dropped"),
+        });
+        Ok(response.boxed())
     }
 
-    fn handle_response_msg(&mut self, request_id: RequestId, response: CommandResponse) {
-        match response {
-            Pong => {
-                println!("Received Pong for request {:?}", request_id);
+    fn handle_response_msg(&mut self, peer: PeerId, request_id: RequestId, response: Envelope<CommandResponse>) {
+        if self.timed_out_requests.remove(&request_id) {
+            return;
+        }
+        let pending = self.pending_requests.remove(&request_id);
+        let (command, elapsed, request_id) = match pending {
+            Some(PendingRequest {
+                sender: Some(sender), ..
+            }) => {
+                let _ = sender.send(Ok(response));
+                return;
             }
-            OtherRes(result) => {
-                println!(
-                    "Received Result for request {:?}: {:?}",
-                    request_id,
-                    String::from_utf8(result)
-                );
+            Some(pending) => (Some(pending.command), Some(pending.sent_at.elapsed()), pending.id),
+            None => (None, None, request_id),
+        };
+        self.emit(NodeEvent::ResponseReceived {
+            peer,
+            request_id,
+            command,
+            elapsed,
+            response,
+        });
+    }
+
+    fn handle_outbound_failure(
+        &mut self,
+        peer: PeerId,
+        request_id: RequestId,
+        error: request_response::OutboundFailure,
+    ) {
+        if self.timed_out_requests.remove(&request_id) {
+            return;
+        }
+        match self.pending_requests.remove(&request_id) {
+            Some(pending) => self.fail_request(pending, error),
+            None => self.emit(NodeEvent::RequestFailed {
+                peer,
+                request_id,
+                command: None,
+                error,
+            }),
+        }
+    }
+
+    // Report the failure of the request and pass it to the receiver of the response, or schedule
+    // a retry if the retry policy allows it.
+    fn fail_request(&mut self, mut pending: PendingRequest, error: request_response::OutboundFailure) {
+        let backoff = match (&self.retry_policy, &pending.retry) {
+            (Some(policy), Some(retry)) if !self.ban_list.is_banned(&retry.peer) => {
+                policy.backoff(retry.attempt, &error, retry.request.body.is_idempotent())
             }
+            _ => None,
+        };
+        if let (Some(backoff), Some(retry)) = (backoff, pending.retry.take()) {
+            self.emit(NodeEvent::RequestRetrying {
+                peer: pending.peer.clone(),
+                request_id: pending.id,
+                command: pending.command,
+                attempt: retry.attempt,
+                backoff,
+            });
+            self.scheduled_retries.push(ScheduledRetry {
+                due: Instant::now() + backoff,
+                target: pending.peer,
+                command: pending.command,
+                retry,
+                sender: pending.sender,
+                error,
+            });
+            return;
+        }
+        if let request_response::OutboundFailure::Timeout = error {
+            self.emit(NodeEvent::RequestTimedOut {
+                peer: pending.peer.clone(),
+                request_id: pending.id,
+                command: pending.command,
+                elapsed: pending.sent_at.elapsed(),
+            });
         }
+        match pending.sender {
+            Some(sender) => {
+                let _ = sender.send(Err(error));
+            }
+            None => {
+                if let request_response::OutboundFailure::Timeout = error {
+                    return;
+                }
+                self.emit(NodeEvent::RequestFailed {
+                    peer: pending.peer,
+                    request_id: pending.id,
+                    command: Some(pending.command),
+                    error,
+                })
+            }
+        }
+    }
+
+    // Send the retries that are due and schedule a wake-up for the next one, returns true if a
+    // retry was sent.
+    fn poll_scheduled_retries(&mut self, cx: &mut Context<'_>) -> bool {
+        let mut sent = false;
+        loop {
+            self.cancel_banned_retries();
+            let now = Instant::now();
+            let (due, scheduled): (Vec<_>, Vec<_>) = std::mem::take(&mut self.scheduled_retries)
+                .into_iter()
+                .partition(|scheduled| scheduled.due <= now);
+            self.scheduled_retries = scheduled;
+            for scheduled in due {
+                self.send_retry(scheduled);
+                sent = true;
+            }
+            let next_due = match self.scheduled_retries.iter().map(|scheduled| scheduled.due).min() {
+                Some(due) => due,
+                None => {
+                    self.retry_timer = None;
+                    break;
+                }
+            };
+            match &self.retry_timer {
+                Some((due, _)) if *due == next_due => {}
+                _ => {
+                    let timer = async_std::task::sleep(next_due.saturating_duration_since(now)).boxed();
+                    self.retry_timer = Some((next_due, timer));
+                }
+            }
+            if let Some((_, timer)) = self.retry_timer.as_mut() {
+                if timer.poll_unpin(cx).is_pending() {
+                    break;
+                }
+            }
+            self.retry_timer = None;
+        }
+        if sent {
+            // msg_proto was polled before the retries were sent, it has to be polled again
+            cx.waker().wake_by_ref();
+        }
+        sent
+    }
+
+    fn send_retry(&mut self, scheduled: ScheduledRetry) {
+        let RetryState {
+            id,
+            peer,
+            request,
+            attempt,
+        } = scheduled.retry;
+        let request_id = self.send_tracked(
+            &scheduled.target,
+            &peer,
+            scheduled.command,
+            request.clone(),
+            scheduled.sender,
+        );
+        self.emit(NodeEvent::RequestResent {
+            peer: scheduled.target.clone(),
+            request_id: id,
+            command: scheduled.command,
+            attempt: attempt + 1,
+        });
+        if let Some(pending) = self.pending_requests.get_mut(&request_id) {
+            pending.id = id;
+            pending.retry = Some(RetryState {
+                id,
+                peer,
+                request,
+                attempt: attempt + 1,
+            });
+        }
+    }
+
+    // Drop the scheduled retries to banned peers, their last failure is reported instead.
+    fn cancel_banned_retries(&mut self) {
+        let ban_list = &self.ban_list;
+        let (cancelled, scheduled): (Vec<_>, Vec<_>) = std::mem::take(&mut self.scheduled_retries)
+            .into_iter()
+            .partition(|scheduled| ban_list.is_banned(&scheduled.retry.peer) || ban_list.is_banned(&scheduled.target));
+        self.scheduled_retries = scheduled;
+        for scheduled in cancelled {
+            self.emit(NodeEvent::RetryCancelled {
+                peer: scheduled.target,
+                request_id: scheduled.retry.id,
+                command: scheduled.command,
+            });
+            if let Some(sender) = scheduled.sender {
+                let _ = sender.send(Err(scheduled.error));
+            }
+        }
+    }
+
+    // Fail the pending requests whose deadline passed and schedule a wake-up for the next one.
+    fn poll_request_timeouts(&mut self, cx: &mut Context<'_>) {
+        loop {
+            let now = Instant::now();
+            let expired: Vec<RequestId> = self
+                .pending_requests
+                .iter()
+                .filter(|(_, pending)| pending.deadline <= now)
+                .map(|(request_id, _)| *request_id)
+                .collect();
+            for request_id in expired {
+                if let Some(pending) = self.pending_requests.remove(&request_id) {
+                    self.timed_out_requests.insert(request_id);
+                    self.fail_request(pending, request_response::OutboundFailure::Timeout);
+                }
+            }
+            let next_deadline = match self.pending_requests.values().map(|pending| pending.deadline).min() {
+                Some(deadline) => deadline,
+                None => {
+                    self.timeout_timer = None;
+                    return;
+                }
+            };
+            match &self.timeout_timer {
+                Some((deadline, _)) if *deadline == next_deadline => {}
+                _ => {
+                    let timer = async_std::task::sleep(next_deadline.saturating_duration_since(now)).boxed();
+                    self.timeout_timer = Some((next_deadline, timer));
+                }
+            }
+            if let Some((_, timer)) = self.timeout_timer.as_mut() {
+                if timer.poll_unpin(cx).is_pending() {
+                    return;
+                }
+            }
+        }
+    }
+
+    // Lift the expired bans and set the timer to the next expiry.
+    fn poll_ban_expiry(&mut self, cx: &mut Context<'_>) {
+        loop {
+            self.ban_list.expire_bans();
+            let next_expiry = match self.ban_list.next_expiry() {
+                Some(expiry) => expiry,
+                None => {
+                    self.ban_timer = None;
+                    return;
+                }
+            };
+            match &self.ban_timer {
+                Some((expiry, _)) if *expiry == next_expiry => {}
+                _ => {
+                    let delay = next_expiry.saturating_duration_since(Instant::now());
+                    self.ban_timer = Some((next_expiry, async_std::task::sleep(delay).boxed()));
+                }
+            }
+            if let Some((_, timer)) = self.ban_timer.as_mut() {
+                if timer.poll_unpin(cx).is_pending() {
+                    return;
+                }
+            }
+            self.ban_timer = None;
+        }
+    }
+
+    // Called by the derived NetworkBehaviour after polling the inner behaviours.
+    fn poll<TEv>(
+        &mut self,
+        cx: &mut Context<'_>,
+        _: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<TEv, ()>> {
+        while let Poll::Ready(Some((channel, response))) = self.pending_responses.poll_next_unpin(cx) {
+            self.msg_proto.send_response(channel, response);
+        }
+        self.poll_ban_expiry(cx);
+        while let Some(Poll::Ready(Some(rejected))) =
+            self.rejected_messages.as_mut().map(|rejected| rejected.poll_next_unpin(cx))
+        {
+            self.emit(NodeEvent::MessageRejected {
+                kind: rejected.kind,
+                reason: rejected.reason,
+            });
+        }
+        // timed out requests may schedule retries, that set new deadlines once they are sent
+        self.poll_request_timeouts(cx);
+        if self.poll_scheduled_retries(cx) {
+            self.poll_request_timeouts(cx);
+        }
+        // a failed bootstrap schedules a retry, whose timer has to be polled as well
+        while self.bootstrap.poll_due(cx).is_ready() {
+            self.start_bootstrap();
+        }
+        Poll::Pending
+    }
+}
+
+fn timestamp_now() -> String {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis())
+        .unwrap_or_default()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_protocol::CommandProtocol;
+    use futures::{executor::block_on, future};
+    use libp2p::{
+        core::{transport::MemoryTransport, upgrade},
+        gossipsub::{GossipsubConfig, MessageAuthenticity},
+        identity::Keypair,
+        kad::record::store::MemoryStoreConfig,
+        mplex::MplexConfig,
+        plaintext::PlainText2Config,
+        ping::PingConfig,
+        request_response::{ProtocolSupport, RequestResponseConfig},
+        Swarm, Transport,
+    };
+    use std::iter;
+
+    // Node that listens on a random memory address, mDNS is disabled.
+    fn node() -> (PeerId, Multiaddr, Swarm<P2PNetworkBehaviour>) {
+        let keys = Keypair::generate_ed25519();
+        let peer_id = PeerId::from(keys.public());
+        let transport = MemoryTransport::default()
+            .upgrade(upgrade::Version::V1)
+            .authenticate(PlainText2Config {
+                local_public_key: keys.public(),
+            })
+            .multiplex(MplexConfig::new());
+        let store = NodeStore::in_memory(peer_id.clone(), MemoryStoreConfig::default());
+        let behaviour = P2PNetworkBehaviour::new(
+            Kademlia::new(peer_id.clone(), store),
+            Toggle::from(None),
+            RequestResponse::new(
+                CommandCodec::default(),
+                iter::once((CommandProtocol::V2, ProtocolSupport::Full)),
+                RequestResponseConfig::default(),
+            ),
+            Identify::new("/test/1.0.0".into(), "test".into(), keys.public()),
+            ping::Ping::new(PingConfig::new()),
+            Gossipsub::new(MessageAuthenticity::Signed(keys), GossipsubConfig::default()),
+        );
+        let mut swarm = Swarm::new(transport, behaviour, peer_id.clone());
+        let addr: Multiaddr = format!("/memory/{}", rand::random::<u64>()).parse().unwrap();
+        Swarm::listen_on(&mut swarm, addr.clone()).unwrap();
+        (peer_id, addr, swarm)
+    }
+
+    // Send a PING from the first to the third node through the second node, forward_for returns
+    // the allow-list of the second node for the id of the first node.
+    fn forward_ping(forward_for: impl FnOnce(PeerId) -> HashSet<PeerId>) -> CommandResponse {
+        let (requester_id, _, mut requester) = node();
+        let (forwarder_id, forwarder_addr, mut forwarder) = node();
+        let (target_id, target_addr, mut target) = node();
+        requester.kademlia.add_address(&forwarder_id, forwarder_addr);
+        forwarder.kademlia.add_address(&target_id, target_addr);
+        forwarder.forward_for = forward_for(requester_id);
+        requester.add_forward_route(target_id.clone(), forwarder_id);
+        let (_, mut receiver) = requester.send_request_with_receiver(&target_id, CommandRequest::Ping);
+        let response = block_on(future::poll_fn(|cx| {
+            for swarm in &mut [&mut requester, &mut forwarder, &mut target] {
+                while let Poll::Ready(Some(_)) = swarm.poll_next_unpin(cx) {}
+            }
+            receiver.poll_unpin(cx)
+        }));
+        response.unwrap().unwrap().body
+    }
+
+    #[test]
+    fn forwards_requests_of_allowed_peers() {
+        assert_eq!(forward_ping(|requester| iter::once(requester).collect()), Pong);
+    }
+
+    #[test]
+    fn rejects_requests_of_other_peers() {
+        let response = forward_ping(|_| iter::once(PeerId::random()).collect());
+        assert!(matches!(response, ErrorRes { code: error_code::FORBIDDEN, .. }));
+    }
+
+    #[test]
+    fn forwarding_is_disabled_by_default() {
+        let response = forward_ping(|_| HashSet::new());
+        assert!(matches!(response, ErrorRes { code: error_code::UNSUPPORTED, .. }));
+    }
+
+    // Send a PUT from the first to the second node, store_writers returns the allow-list of the
+    // second node for the id of the first node.
+    fn put(store_writers: impl FnOnce(PeerId) -> Option<HashSet<PeerId>>) -> (CommandResponse, Option<Vec<u8>>) {
+        let (requester_id, _, mut requester) = node();
+        let (store_id, store_addr, mut store) = node();
+        requester.kademlia.add_address(&store_id, store_addr);
+        store.store_writers = store_writers(requester_id);
+        let request = CommandRequest::Put {
+            key: "key".to_string(),
+            value: b"value".to_vec(),
+        };
+        let (_, mut receiver) = requester.send_request_with_receiver(&store_id, request);
+        let response = block_on(future::poll_fn(|cx| {
+            for swarm in &mut [&mut requester, &mut store] {
+                while let Poll::Ready(Some(_)) = swarm.poll_next_unpin(cx) {}
+            }
+            receiver.poll_unpin(cx)
+        }));
+        (response.unwrap().unwrap().body, store.store.get("key").cloned())
+    }
+
+    #[test]
+    fn all_peers_may_write_without_store_writers() {
+        assert_eq!(put(|_| None), (Stored, Some(b"value".to_vec())));
+    }
+
+    #[test]
+    fn store_writers_may_write() {
+        assert_eq!(
+            put(|requester| Some(iter::once(requester).collect())),
+            (Stored, Some(b"value".to_vec()))
+        );
+    }
+
+    #[test]
+    fn other_peers_may_not_write() {
+        let (response, value) = put(|_| Some(iter::once(PeerId::random()).collect()));
+        assert!(matches!(response, ErrorRes { code: error_code::FORBIDDEN, .. }));
+        assert_eq!(value, None);
+    }
+
+    #[test]
+    fn every_subscriber_receives_the_events() {
+        let (_, _, mut swarm) = node();
+        let mut first = swarm.event_stream();
+        let mut second = swarm.event_stream();
+        swarm.emit(NodeEvent::AllPeersLost);
+        for events in &mut [&mut first, &mut second] {
+            assert!(matches!(*events.try_next().unwrap().unwrap(), NodeEvent::AllPeersLost));
+        }
+    }
+
+    #[test]
+    fn reports_dropped_events_to_lagging_subscribers() {
+        let (_, _, mut swarm) = node();
+        let mut events = swarm.event_stream();
+        // the channel buffers one more event for its sender
+        for _ in 0..EVENT_BUFFER_SIZE + 3 {
+            swarm.emit(NodeEvent::AllPeersLost);
+        }
+        for _ in 0..EVENT_BUFFER_SIZE + 1 {
+            assert!(matches!(*events.try_next().unwrap().unwrap(), NodeEvent::AllPeersLost));
+        }
+        swarm.emit(NodeEvent::AllPeersLost);
+        assert!(matches!(*events.try_next().unwrap().unwrap(), NodeEvent::EventsDropped { count: 2 }));
+        assert!(matches!(*events.try_next().unwrap().unwrap(), NodeEvent::AllPeersLost));
+    }
+
+    #[test]
+    fn closed_subscribers_are_removed() {
+        let (_, _, mut swarm) = node();
+        drop(swarm.event_stream());
+        swarm.emit(NodeEvent::AllPeersLost);
+        assert!(swarm.subscribers.is_empty());
+    }
+
+    #[test]
+    fn forwarded_requests_wait_longer_than_the_forwarding_peer() {
+        let mut timeouts = RequestTimeouts::default();
+        timeouts.commands.insert("GET".into(), Duration::from_secs(5));
+        assert_eq!(timeouts.forward("GET"), Duration::from_secs(10));
+        assert_eq!(timeouts.forward("PING"), Duration::from_secs(20));
+        assert_eq!(timeouts.max(), Duration::from_secs(20));
+        timeouts.commands.insert("FORWARD".into(), Duration::from_secs(30));
+        assert_eq!(timeouts.forward("GET"), Duration::from_secs(30));
+        assert_eq!(timeouts.max(), Duration::from_secs(30));
     }
 }
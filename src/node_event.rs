@@ -0,0 +1,576 @@
+// Events of the network activity of a node.
+// P2PNetworkBehaviour sends them to the subscribers of its event stream, so that applications can
+// react to them. The Display implementation describes an event in a single line, or in multiple
+// lines for responses with details.
+
+use crate::command_protocol::{header, CommandResponse, Envelope, Headers};
+use libp2p::{
+    core::{ConnectedPoint, Multiaddr},
+    gossipsub::{GossipsubMessage, MessageId, TopicHash},
+    kad::{
+        record::Key, AddProviderError, BootstrapError, BootstrapOk, GetProvidersError, GetRecordError,
+        PutRecordError, QueryId, Record,
+    },
+    ping::PingFailure,
+    request_response::{InboundFailure, OutboundFailure, RequestId},
+    swarm::ProtocolsHandlerUpgrErr,
+    PeerId,
+};
+use std::{fmt, io, time::Duration};
+
+#[derive(Debug)]
+pub enum NodeEvent {
+    ConnectionEstablished {
+        peer: PeerId,
+        endpoint: ConnectedPoint,
+    },
+    ConnectionClosed {
+        peer: PeerId,
+        endpoint: ConnectedPoint,
+    },
+    // the connection of the peer exceeded a connection limit and is closed
+    ConnectionLimitReached {
+        peer: PeerId,
+    },
+    // other events of the swarm, e.g. new listen addresses or dial failures
+    Swarm {
+        description: String,
+    },
+    // the peer was banned manually or because its score dropped too low
+    PeerBanned {
+        peer: PeerId,
+        score: i32,
+    },
+    PeerUnbanned {
+        peer: PeerId,
+    },
+    // a peer in the local network was discovered via mDNS
+    PeerDiscovered {
+        peer: PeerId,
+        addr: Multiaddr,
+    },
+    // the mDNS record of a discovered peer expired
+    PeerExpired {
+        peer: PeerId,
+        addr: Multiaddr,
+    },
+    PeerIdentified {
+        peer: PeerId,
+        agent_version: String,
+        observed_addr: Multiaddr,
+    },
+    IdentifyFailed {
+        peer: PeerId,
+        error: ProtocolsHandlerUpgrErr<io::Error>,
+    },
+    PingFailed {
+        peer: PeerId,
+        consecutive_failures: u32,
+        error: PingFailure,
+    },
+    // the peer failed too many pings and was removed from the routing table
+    PeerUnresponsive {
+        peer: PeerId,
+    },
+    // the routing table became empty, the node bootstraps again
+    AllPeersLost,
+    RequestReceived {
+        peer: PeerId,
+        command: &'static str,
+        headers: Headers,
+    },
+    RequestRateLimited {
+        peer: PeerId,
+        command: &'static str,
+        retry_after: Duration,
+    },
+    // an inbound request of the peer is forwarded to the target
+    RequestForwarded {
+        peer: PeerId,
+        target: PeerId,
+        command: &'static str,
+    },
+    // the codec rejected a request or response because it was too large or not read in time
+    MessageRejected {
+        kind: &'static str,
+        reason: String,
+    },
+    InboundFailure {
+        peer: PeerId,
+        request_id: RequestId,
+        error: InboundFailure,
+    },
+    // response to a request that was not sent with a receiver, command and elapsed are None if
+    // the request is unknown
+    ResponseReceived {
+        peer: PeerId,
+        request_id: RequestId,
+        command: Option<&'static str>,
+        elapsed: Option<Duration>,
+        response: Envelope<CommandResponse>,
+    },
+    RequestTimedOut {
+        peer: PeerId,
+        request_id: RequestId,
+        command: &'static str,
+        elapsed: Duration,
+    },
+    // failure of a request that was not sent with a receiver
+    RequestFailed {
+        peer: PeerId,
+        request_id: RequestId,
+        command: Option<&'static str>,
+        error: OutboundFailure,
+    },
+    RequestRetrying {
+        peer: PeerId,
+        request_id: RequestId,
+        command: &'static str,
+        attempt: u32,
+        backoff: Duration,
+    },
+    // a failed request was sent again, request_id is the id of the first attempt
+    RequestResent {
+        peer: PeerId,
+        request_id: RequestId,
+        command: &'static str,
+        attempt: u32,
+    },
+    // a scheduled retry was dropped because the peer was banned
+    RetryCancelled {
+        peer: PeerId,
+        request_id: RequestId,
+        command: &'static str,
+    },
+    GossipMessage {
+        propagation_source: PeerId,
+        id: MessageId,
+        message: GossipsubMessage,
+    },
+    GossipMessageRejected {
+        propagation_source: PeerId,
+        id: MessageId,
+    },
+    TopicSubscribed {
+        peer: PeerId,
+        topic: TopicHash,
+    },
+    TopicUnsubscribed {
+        peer: PeerId,
+        topic: TopicHash,
+    },
+    BootstrapStarted {
+        query: QueryId,
+    },
+    BootstrapProgress {
+        query: QueryId,
+        result: Result<BootstrapOk, BootstrapError>,
+    },
+    // the node does not know any peers to bootstrap with
+    BootstrapFailed {
+        retry_in: Duration,
+    },
+    BootstrapFoundNoPeers {
+        retry_in: Duration,
+    },
+    // the bootstrap finished with peers in the routing table
+    BootstrapFinished {
+        peers: usize,
+    },
+    RecordFound {
+        query: QueryId,
+        peer: Option<PeerId>,
+        record: Record,
+    },
+    GetRecordFailed {
+        query: QueryId,
+        error: GetRecordError,
+    },
+    RecordStored {
+        query: QueryId,
+        key: Key,
+    },
+    PutRecordFailed {
+        query: QueryId,
+        error: PutRecordError,
+    },
+    // providers of a get_providers query that was not started with a receiver
+    ProvidersFound {
+        query: QueryId,
+        key: Key,
+        providers: Vec<PeerId>,
+    },
+    GetProvidersFailed {
+        query: QueryId,
+        error: GetProvidersError,
+    },
+    ProvidingStarted {
+        query: QueryId,
+        key: Key,
+    },
+    StartProvidingFailed {
+        query: QueryId,
+        error: AddProviderError,
+    },
+    ProviderRepublished {
+        key: Key,
+    },
+    RepublishProviderFailed {
+        error: AddProviderError,
+    },
+    // the subscriber did not keep up and missed events
+    EventsDropped {
+        count: u64,
+    },
+}
+
+impl NodeEvent {
+    // Whether the event reports a failure, e.g. to print it to stderr.
+    pub fn is_failure(&self) -> bool {
+        match self {
+            NodeEvent::IdentifyFailed { .. }
+            | NodeEvent::PingFailed { .. }
+            | NodeEvent::InboundFailure { .. }
+            | NodeEvent::MessageRejected { .. }
+            | NodeEvent::RequestTimedOut { .. }
+            | NodeEvent::RequestFailed { .. }
+            | NodeEvent::RetryCancelled { .. }
+            | NodeEvent::GossipMessageRejected { .. }
+            | NodeEvent::BootstrapProgress { result: Err(_), .. }
+            | NodeEvent::BootstrapFailed { .. }
+            | NodeEvent::BootstrapFoundNoPeers { .. }
+            | NodeEvent::GetRecordFailed { .. }
+            | NodeEvent::PutRecordFailed { .. }
+            | NodeEvent::GetProvidersFailed { .. }
+            | NodeEvent::StartProvidingFailed { .. }
+            | NodeEvent::RepublishProviderFailed { .. }
+            | NodeEvent::EventsDropped { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for NodeEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeEvent::ConnectionEstablished { peer, endpoint } => {
+                write!(f, "Connected to peer {:?} at {}", peer, endpoint.get_remote_address())
+            }
+            NodeEvent::ConnectionClosed { peer, endpoint } => write!(
+                f,
+                "Connection to peer {:?} at {} closed",
+                peer,
+                endpoint.get_remote_address()
+            ),
+            NodeEvent::PeerDiscovered { peer, addr } => write!(f, "Discovered peer {:?} at {}", peer, addr),
+            NodeEvent::PeerExpired { peer, addr } => {
+                write!(f, "Removed expired address {} of peer {:?}", addr, peer)
+            }
+            NodeEvent::PeerIdentified {
+                peer,
+                agent_version,
+                observed_addr,
+            } => write!(
+                f,
+                "Identified peer {:?} ({}), it observed us at {}",
+                peer, agent_version, observed_addr
+            ),
+            NodeEvent::PingFailed {
+                peer,
+                consecutive_failures,
+                error,
+            } => write!(
+                f,
+                "Ping to peer {:?} failed ({} in a row): {:?}",
+                peer, consecutive_failures, error
+            ),
+            NodeEvent::PeerUnresponsive { peer } => {
+                write!(f, "Removing unresponsive peer {:?} from the routing table", peer)
+            }
+            NodeEvent::RequestReceived { peer, command, headers } => {
+                write!(f, "Received {} request from peer {:?}", command, peer)?;
+                if let Some(trace_id) = headers.get(header::TRACE_ID) {
+                    write!(f, " with trace-id {}", trace_id)?;
+                }
+                Ok(())
+            }
+            NodeEvent::InboundFailure {
+                peer,
+                request_id,
+                error,
+            } => write!(
+                f,
+                "Inbound Failure for request {:?} to peer: {:?}: {:?}",
+                request_id, peer, error
+            ),
+            NodeEvent::ResponseReceived {
+                peer,
+                request_id,
+                command,
+                elapsed,
+                response,
+            } => {
+                match (command, elapsed) {
+                    (Some(command), Some(elapsed)) => write!(
+                        f,
+                        "Received response to {} request {:?} from peer {:?} after {:?}: ",
+                        command, request_id, peer, elapsed
+                    )?,
+                    _ => write!(
+                        f,
+                        "Received response to unknown request {:?} from peer {:?}: ",
+                        request_id, peer
+                    )?,
+                }
+                fmt_response(f, &response.body)?;
+                if let Some(trace_id) = response.header(header::TRACE_ID) {
+                    write!(f, "\nReceived response with trace-id {}", trace_id)?;
+                }
+                if let Some(retry_after) = response.header(header::RETRY_AFTER) {
+                    write!(f, "\nRequest {:?} may be retried after {} ms", request_id, retry_after)?;
+                }
+                Ok(())
+            }
+            NodeEvent::RequestTimedOut {
+                peer,
+                request_id,
+                command,
+                elapsed,
+            } => write!(
+                f,
+                "{} request {:?} to peer {:?} timed out after {:?}",
+                command, request_id, peer, elapsed
+            ),
+            NodeEvent::RequestFailed {
+                peer,
+                request_id,
+                command,
+                error,
+            } => write!(
+                f,
+                "Outbound Failure for {} request {:?} to peer {:?}: {:?}",
+                command.unwrap_or("unknown"),
+                request_id,
+                peer,
+                error
+            ),
+            NodeEvent::RequestRetrying {
+                peer,
+                request_id,
+                command,
+                attempt,
+                backoff,
+            } => write!(
+                f,
+                "{} request {:?} to peer {:?} failed after attempt {}, retrying in {:?}",
+                command, request_id, peer, attempt, backoff
+            ),
+            NodeEvent::RetryCancelled {
+                peer,
+                request_id,
+                command,
+            } => write!(
+                f,
+                "Cancelled retry of {} request {:?} to banned peer {:?}",
+                command, request_id, peer
+            ),
+            NodeEvent::GossipMessage { id, message, .. } => write!(
+                f,
+                "Got message {:?} on topics {:?} from peer {:?}: {:?}",
+                id,
+                message.topics,
+                message.source,
+                String::from_utf8_lossy(&message.data)
+            ),
+            NodeEvent::GossipMessageRejected {
+                propagation_source,
+                id,
+            } => write!(f, "Rejected message {:?} from peer {:?}", id, propagation_source),
+            NodeEvent::TopicSubscribed { peer, topic } => {
+                write!(f, "Peer {:?} subscribed to {:?}", peer, topic)
+            }
+            NodeEvent::TopicUnsubscribed { peer, topic } => {
+                write!(f, "Peer {:?} unsubscribed from {:?}", peer, topic)
+            }
+            NodeEvent::BootstrapProgress {
+                query,
+                result: Ok(BootstrapOk { peer, num_remaining }),
+            } => write!(
+                f,
+                "Bootstrap query {:?} reached peer {:?}, {} bucket refreshes remaining",
+                query, peer, num_remaining
+            ),
+            NodeEvent::BootstrapProgress {
+                query,
+                result: Err(BootstrapError::Timeout { peer, num_remaining }),
+            } => write!(
+                f,
+                "Timeout of bootstrap query {:?} for peer {:?}, {:?} bucket refreshes remaining",
+                query, peer, num_remaining
+            ),
+            NodeEvent::RecordFound { query, peer, record } => write!(
+                f,
+                "Got record {:?} {:?} from peer {:?} for query {:?}",
+                String::from_utf8_lossy(record.key.as_ref()),
+                String::from_utf8_lossy(&record.value),
+                peer,
+                query
+            ),
+            NodeEvent::GetRecordFailed { query, error } => match error {
+                GetRecordError::NotFound { key, .. } => write!(
+                    f,
+                    "Record {:?} not found for query {:?}",
+                    String::from_utf8_lossy(key.as_ref()),
+                    query
+                ),
+                GetRecordError::QuorumFailed { key, records, quorum } => write!(
+                    f,
+                    "Quorum {} failed for record {:?}, got {} record(s) for query {:?}",
+                    quorum,
+                    String::from_utf8_lossy(key.as_ref()),
+                    records.len(),
+                    query
+                ),
+                GetRecordError::Timeout { key, records, quorum } => write!(
+                    f,
+                    "Timeout while getting record {:?} with quorum {}, got {} record(s) for query {:?}",
+                    String::from_utf8_lossy(key.as_ref()),
+                    quorum,
+                    records.len(),
+                    query
+                ),
+            },
+            NodeEvent::RecordStored { query, key } => write!(
+                f,
+                "Successfully stored record {:?} for query {:?}",
+                String::from_utf8_lossy(key.as_ref()),
+                query
+            ),
+            NodeEvent::PutRecordFailed { query, error } => match error {
+                PutRecordError::QuorumFailed { key, success, quorum } => write!(
+                    f,
+                    "Quorum {} failed for record {:?}, stored on {} peer(s) for query {:?}",
+                    quorum,
+                    String::from_utf8_lossy(key.as_ref()),
+                    success.len(),
+                    query
+                ),
+                PutRecordError::Timeout { key, success, quorum } => write!(
+                    f,
+                    "Timeout while storing record {:?} with quorum {}, stored on {} peer(s) for query {:?}",
+                    String::from_utf8_lossy(key.as_ref()),
+                    quorum,
+                    success.len(),
+                    query
+                ),
+            },
+            NodeEvent::ProvidersFound { query, key, providers } => {
+                if providers.is_empty() {
+                    return write!(
+                        f,
+                        "No providers found for key {:?} for query {:?}",
+                        String::from_utf8_lossy(key.as_ref()),
+                        query
+                    );
+                }
+                write!(
+                    f,
+                    "Peers {:?} provide key {:?} for query {:?}",
+                    providers,
+                    String::from_utf8_lossy(key.as_ref()),
+                    query
+                )
+            }
+            NodeEvent::GetProvidersFailed { query, error } => {
+                write!(f, "Failed to get providers for query {:?}: {:?}", query, error)
+            }
+            NodeEvent::ProvidingStarted { query, key } => write!(
+                f,
+                "Successfully announced provider record for key {:?} for query {:?}",
+                String::from_utf8_lossy(key.as_ref()),
+                query
+            ),
+            NodeEvent::StartProvidingFailed { query, error } => write!(
+                f,
+                "Failed to announce provider record for query {:?}: {:?}",
+                query, error
+            ),
+            NodeEvent::ProviderRepublished { key } => write!(
+                f,
+                "Re-announced provider record for key {:?}",
+                String::from_utf8_lossy(key.as_ref())
+            ),
+            NodeEvent::RepublishProviderFailed { error } => {
+                write!(f, "Failed to re-announce provider record: {:?}", error)
+            }
+            NodeEvent::ConnectionLimitReached { peer } => {
+                write!(f, "Connection limit reached, disconnecting peer {:?}", peer)
+            }
+            NodeEvent::Swarm { description } => write!(f, "{}", description),
+            NodeEvent::PeerBanned { peer, score } => write!(f, "Banned peer {:?} with score {}", peer, score),
+            NodeEvent::PeerUnbanned { peer } => write!(f, "Ban of peer {:?} lifted", peer),
+            NodeEvent::IdentifyFailed { peer, error } => {
+                write!(f, "Identify error with peer {:?}: {:?}", peer, error)
+            }
+            NodeEvent::MessageRejected { kind, reason } => write!(f, "Rejected {}: {}", kind, reason),
+            NodeEvent::AllPeersLost => write!(f, "Lost all peers, bootstrapping again"),
+            NodeEvent::RequestRateLimited {
+                peer,
+                command,
+                retry_after,
+            } => write!(
+                f,
+                "Rate limited {} request of peer {:?}, retry after {:?}",
+                command, peer, retry_after
+            ),
+            NodeEvent::RequestForwarded { peer, target, command } => write!(
+                f,
+                "Forwarding {} request from peer {:?} to peer {:?}",
+                command, peer, target
+            ),
+            NodeEvent::RequestResent {
+                peer,
+                request_id,
+                command,
+                attempt,
+            } => write!(
+                f,
+                "Retrying {} request {:?} to peer {:?}, attempt {}",
+                command, request_id, peer, attempt
+            ),
+            NodeEvent::BootstrapStarted { query } => write!(f, "Bootstrapping with query {:?}", query),
+            NodeEvent::BootstrapFailed { retry_in } => {
+                write!(f, "Could not bootstrap without known peers, retrying in {:?}", retry_in)
+            }
+            NodeEvent::BootstrapFoundNoPeers { retry_in } => {
+                write!(f, "Bootstrap did not find any peers, retrying in {:?}", retry_in)
+            }
+            NodeEvent::BootstrapFinished { peers } => {
+                write!(f, "Bootstrap finished, {} peers in the routing table", peers)
+            }
+            NodeEvent::EventsDropped { count } => write!(f, "Dropped {} events, the subscriber did not keep up", count),
+        }
+    }
+}
+
+fn fmt_response(f: &mut fmt::Formatter<'_>, response: &CommandResponse) -> fmt::Result {
+    match response {
+        CommandResponse::Pong => write!(f, "Pong"),
+        CommandResponse::Other(result) => write!(f, "Result {:?}", String::from_utf8_lossy(result)),
+        CommandResponse::Error {
+            code,
+            reason,
+            details,
+        } => {
+            write!(f, "Error {} {}", code, reason)?;
+            if let Some(details) = details {
+                write!(f, "\nDetails: {:?}", String::from_utf8_lossy(details))?;
+            }
+            Ok(())
+        }
+        CommandResponse::Value(value) => write!(f, "Value {:?}", String::from_utf8_lossy(value)),
+        CommandResponse::Stored => write!(f, "Value stored"),
+        CommandResponse::Deleted => write!(f, "Value deleted"),
+        CommandResponse::Keys(keys) => write!(f, "Keys {:?}", keys),
+    }
+}
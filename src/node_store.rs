@@ -0,0 +1,482 @@
+// Kademlia record store of a node.
+// Records and provider records are kept in a MemoryStore, that enforces the size limits. If a
+// path is configured, every change is additionally appended to a log file, that is replayed on
+// startup so that records survive restarts. The log is compacted by rewriting it from the current
+// state once it contains too many outdated entries, expired records are dropped on compaction.
+
+use crate::command_protocol::base64_bytes;
+use libp2p::{
+    core::Multiaddr,
+    kad::record::{
+        store::{MemoryStore, MemoryStoreConfig, RecordStore, Result},
+        Key, ProviderRecord, Record,
+    },
+    PeerId,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// Minimal number of log entries before the log is compacted.
+const MIN_COMPACTION_ENTRIES: usize = 1000;
+
+pub struct NodeStore {
+    memory: MemoryStore,
+    // number of records in the MemoryStore, so that they do not have to be counted on every write
+    records: usize,
+    // keys for which provider records are stored, the MemoryStore does not expose them
+    provider_keys: HashSet<Key>,
+    log: Option<StoreLog>,
+}
+
+struct StoreLog {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    // number of entries in the log file
+    entries: usize,
+}
+
+// Entry of the log file, times are milliseconds since the unix epoch.
+#[derive(Serialize, Deserialize)]
+enum LogEntry {
+    PutRecord {
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "base64_bytes")]
+        value: Vec<u8>,
+        publisher: Option<String>,
+        expires: Option<u64>,
+    },
+    RemoveRecord {
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
+    },
+    AddProvider {
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
+        provider: String,
+        expires: Option<u64>,
+        addresses: Vec<String>,
+    },
+    RemoveProvider {
+        #[serde(with = "base64_bytes")]
+        key: Vec<u8>,
+        provider: String,
+    },
+}
+
+impl NodeStore {
+    // Store that only keeps the records in memory.
+    pub fn in_memory(local_id: PeerId, config: MemoryStoreConfig) -> Self {
+        NodeStore {
+            memory: MemoryStore::with_config(local_id, config),
+            records: 0,
+            provider_keys: HashSet::new(),
+            log: None,
+        }
+    }
+
+    // Store that persists the records in the log file at path, existing records are loaded. An
+    // incomplete last entry is skipped, corrupt entries before it fail the whole log.
+    pub fn open(local_id: PeerId, config: MemoryStoreConfig, path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut store = NodeStore::in_memory(local_id, config);
+        if path.exists() {
+            let mut lines = BufReader::new(File::open(&path)?).split(b'\n').enumerate().peekable();
+            while let Some((i, line)) = lines.next() {
+                let line = line?;
+                if line.is_empty() {
+                    continue;
+                }
+                match serde_json::from_slice(&line) {
+                    Ok(entry) => store.apply(entry),
+                    // a crash while appending leaves an incomplete last line, the compaction below
+                    // removes it from the file
+                    Err(e) if lines.peek().is_none() => eprintln!(
+                        "Ignoring incomplete last entry of record store log {}: {}",
+                        path.display(),
+                        e
+                    ),
+                    Err(e) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("corrupt entry in line {} of {}: {}", i + 1, path.display(), e),
+                        ))
+                    }
+                }
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        store.log = Some(StoreLog {
+            path,
+            writer: BufWriter::new(file),
+            entries: 0,
+        });
+        // drop outdated entries and expired records of the previous run
+        store.compact()?;
+        Ok(store)
+    }
+
+    // Apply a log entry to the in-memory state, expired entries are skipped.
+    fn apply(&mut self, entry: LogEntry) {
+        let now = SystemTime::now();
+        match entry {
+            LogEntry::PutRecord {
+                key,
+                value,
+                publisher,
+                expires,
+            } => {
+                if is_expired(expires, now) {
+                    return;
+                }
+                let record = Record {
+                    key: Key::from(key),
+                    value,
+                    publisher: publisher.and_then(|p| PeerId::from_str(&p).ok()),
+                    expires: expires.map(to_instant),
+                };
+                if let Err(e) = self.put_record(record) {
+                    eprintln!("Could not load record: {:?}", e);
+                }
+            }
+            LogEntry::RemoveRecord { key } => self.remove_record(&Key::from(key)),
+            LogEntry::AddProvider {
+                key,
+                provider,
+                expires,
+                addresses,
+            } => {
+                if is_expired(expires, now) {
+                    return;
+                }
+                if let Ok(provider) = PeerId::from_str(&provider) {
+                    let key = Key::from(key);
+                    let addresses = addresses.iter().filter_map(|a| a.parse::<Multiaddr>().ok()).collect();
+                    let mut record = ProviderRecord::new(key.clone(), provider, addresses);
+                    record.expires = expires.map(to_instant);
+                    if let Err(e) = self.memory.add_provider(record) {
+                        eprintln!("Could not load provider record: {:?}", e);
+                    } else {
+                        self.provider_keys.insert(key);
+                    }
+                }
+            }
+            LogEntry::RemoveProvider { key, provider } => {
+                if let Ok(provider) = PeerId::from_str(&provider) {
+                    self.remove_provider_key(&Key::from(key), &provider);
+                }
+            }
+        }
+    }
+
+    fn put_record(&mut self, record: Record) -> Result<()> {
+        let is_new = self.memory.get(&record.key).is_none();
+        self.memory.put(record)?;
+        if is_new {
+            self.records += 1;
+        }
+        Ok(())
+    }
+
+    fn remove_record(&mut self, key: &Key) {
+        if self.memory.get(key).is_some() {
+            self.memory.remove(key);
+            self.records -= 1;
+        }
+    }
+
+    fn remove_provider_key(&mut self, key: &Key, provider: &PeerId) {
+        self.memory.remove_provider(key, provider);
+        if self.memory.providers(key).is_empty() {
+            self.provider_keys.remove(key);
+        }
+    }
+
+    // Append the entry to the log and compact it if it grew too large. I/O errors are logged,
+    // the in-memory state stays valid.
+    fn append(&mut self, entry: LogEntry) {
+        let log = match self.log.as_mut() {
+            Some(log) => log,
+            None => return,
+        };
+        if let Err(e) = write_entry(&mut log.writer, &entry).and_then(|_| log.writer.flush()) {
+            eprintln!("Could not persist record store entry: {:?}", e);
+            return;
+        }
+        log.entries += 1;
+        let live_entries = self.records + self.provider_keys.len();
+        if log.entries > MIN_COMPACTION_ENTRIES.max(2 * live_entries) {
+            if let Err(e) = self.compact() {
+                eprintln!("Could not compact record store: {:?}", e);
+            }
+        }
+    }
+
+    // Rewrite the log file so that it only contains the current, not expired records.
+    pub fn compact(&mut self) -> io::Result<()> {
+        let path = match &self.log {
+            Some(log) => log.path.clone(),
+            None => return Ok(()),
+        };
+        let now = Instant::now();
+        let mut entries = Vec::new();
+        for record in self.memory.records() {
+            if !record.is_expired(now) {
+                entries.push(put_record_entry(&record));
+            }
+        }
+        for key in &self.provider_keys {
+            for record in self.memory.providers(key) {
+                if !record.is_expired(now) {
+                    entries.push(add_provider_entry(&record));
+                }
+            }
+        }
+
+        let tmp_path = path.with_extension("compact");
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            for entry in &entries {
+                write_entry(&mut writer, entry)?;
+            }
+            writer.flush()?;
+        }
+        fs::rename(&tmp_path, &path)?;
+        let file = OpenOptions::new().append(true).open(&path)?;
+        self.log = Some(StoreLog {
+            path,
+            writer: BufWriter::new(file),
+            entries: entries.len(),
+        });
+        Ok(())
+    }
+}
+
+impl<'a> RecordStore<'a> for NodeStore {
+    type RecordsIter = <MemoryStore as RecordStore<'a>>::RecordsIter;
+    type ProvidedIter = <MemoryStore as RecordStore<'a>>::ProvidedIter;
+
+    fn get(&'a self, k: &Key) -> Option<Cow<'_, Record>> {
+        self.memory.get(k)
+    }
+
+    fn put(&'a mut self, r: Record) -> Result<()> {
+        let entry = put_record_entry(&r);
+        self.put_record(r)?;
+        self.append(entry);
+        Ok(())
+    }
+
+    fn remove(&'a mut self, k: &Key) {
+        self.remove_record(k);
+        self.append(LogEntry::RemoveRecord { key: k.to_vec() });
+    }
+
+    fn records(&'a self) -> Self::RecordsIter {
+        self.memory.records()
+    }
+
+    fn add_provider(&'a mut self, record: ProviderRecord) -> Result<()> {
+        let entry = add_provider_entry(&record);
+        let key = record.key.clone();
+        self.memory.add_provider(record)?;
+        self.provider_keys.insert(key);
+        self.append(entry);
+        Ok(())
+    }
+
+    fn providers(&'a self, key: &Key) -> Vec<ProviderRecord> {
+        self.memory.providers(key)
+    }
+
+    fn provided(&'a self) -> Self::ProvidedIter {
+        self.memory.provided()
+    }
+
+    fn remove_provider(&'a mut self, k: &Key, p: &PeerId) {
+        self.remove_provider_key(k, p);
+        self.append(LogEntry::RemoveProvider {
+            key: k.to_vec(),
+            provider: p.to_base58(),
+        });
+    }
+}
+
+fn put_record_entry(record: &Record) -> LogEntry {
+    LogEntry::PutRecord {
+        key: record.key.to_vec(),
+        value: record.value.clone(),
+        publisher: record.publisher.as_ref().map(PeerId::to_base58),
+        expires: record.expires.map(to_unix_millis),
+    }
+}
+
+fn add_provider_entry(record: &ProviderRecord) -> LogEntry {
+    LogEntry::AddProvider {
+        key: record.key.to_vec(),
+        provider: record.provider.to_base58(),
+        expires: record.expires.map(to_unix_millis),
+        addresses: record.addresses.iter().map(Multiaddr::to_string).collect(),
+    }
+}
+
+fn write_entry(writer: &mut impl Write, entry: &LogEntry) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, entry).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    writer.write_all(b"\n")
+}
+
+fn is_expired(expires: Option<u64>, now: SystemTime) -> bool {
+    expires.map_or(false, |expires| UNIX_EPOCH + Duration::from_millis(expires) <= now)
+}
+
+fn to_unix_millis(instant: Instant) -> u64 {
+    let now = Instant::now();
+    let time = if instant >= now {
+        SystemTime::now() + (instant - now)
+    } else {
+        SystemTime::now() - (now - instant)
+    };
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn to_instant(unix_millis: u64) -> Instant {
+    let time = UNIX_EPOCH + Duration::from_millis(unix_millis);
+    match time.duration_since(SystemTime::now()) {
+        Ok(remaining) => Instant::now() + remaining,
+        Err(_) => Instant::now(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Log file in the temp directory that is removed when the test ends.
+    struct TempLog(PathBuf);
+
+    impl TempLog {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("node-store-{}-{}.log", name, std::process::id()));
+            let _ = fs::remove_file(&path);
+            TempLog(path)
+        }
+
+        fn lines(&self) -> usize {
+            fs::read_to_string(&self.0).unwrap().lines().count()
+        }
+    }
+
+    impl Drop for TempLog {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn open(log: &TempLog) -> io::Result<NodeStore> {
+        NodeStore::open(PeerId::random(), MemoryStoreConfig::default(), &log.0)
+    }
+
+    fn record(key: &str, value: &str) -> Record {
+        Record::new(Key::from(key.as_bytes().to_vec()), value.as_bytes().to_vec())
+    }
+
+    fn value(store: &NodeStore, key: &str) -> Option<Vec<u8>> {
+        store.get(&Key::from(key.as_bytes().to_vec())).map(|record| record.value.clone())
+    }
+
+    #[test]
+    fn replays_log_on_open() {
+        let log = TempLog::new("replay");
+        {
+            let mut store = open(&log).unwrap();
+            store.put(record("a", "1")).unwrap();
+            store.put(record("b", "2")).unwrap();
+            store.put(record("a", "3")).unwrap();
+            store.remove(&Key::from(b"b".to_vec()));
+        }
+        let store = open(&log).unwrap();
+        assert_eq!(value(&store, "a"), Some(b"3".to_vec()));
+        assert_eq!(value(&store, "b"), None);
+    }
+
+    #[test]
+    fn compaction_keeps_only_live_records() {
+        let log = TempLog::new("compact");
+        let mut store = open(&log).unwrap();
+        for i in 0..10 {
+            store.put(record("a", &i.to_string())).unwrap();
+        }
+        store.put(record("b", "1")).unwrap();
+        assert_eq!(log.lines(), 11);
+        store.compact().unwrap();
+        assert_eq!(log.lines(), 2);
+        drop(store);
+        let store = open(&log).unwrap();
+        assert_eq!(value(&store, "a"), Some(b"9".to_vec()));
+        assert_eq!(value(&store, "b"), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn counts_records_on_put_remove_and_replay() {
+        let log = TempLog::new("count");
+        {
+            let mut store = open(&log).unwrap();
+            store.put(record("a", "1")).unwrap();
+            store.put(record("a", "2")).unwrap();
+            store.put(record("b", "1")).unwrap();
+            store.remove(&Key::from(b"b".to_vec()));
+            store.remove(&Key::from(b"b".to_vec()));
+            assert_eq!(store.records, 1);
+        }
+        assert_eq!(open(&log).unwrap().records, 1);
+    }
+
+    #[test]
+    fn expired_records_are_dropped() {
+        let log = TempLog::new("expired");
+        {
+            let mut store = open(&log).unwrap();
+            let mut expired = record("a", "1");
+            expired.expires = Some(Instant::now());
+            store.put(expired).unwrap();
+            store.put(record("b", "2")).unwrap();
+        }
+        let store = open(&log).unwrap();
+        assert_eq!(value(&store, "a"), None);
+        assert_eq!(value(&store, "b"), Some(b"2".to_vec()));
+        assert_eq!(log.lines(), 1);
+    }
+
+    #[test]
+    fn skips_incomplete_last_entry() {
+        let log = TempLog::new("incomplete");
+        open(&log).unwrap().put(record("a", "1")).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&log.0).unwrap();
+        file.write_all(b"{\"PutRecord\":{\"key\":").unwrap();
+        drop(file);
+        let store = open(&log).unwrap();
+        assert_eq!(value(&store, "a"), Some(b"1".to_vec()));
+        // the incomplete entry was removed by the compaction
+        assert_eq!(log.lines(), 1);
+    }
+
+    #[test]
+    fn fails_on_corrupt_entry_before_the_end() {
+        let log = TempLog::new("corrupt");
+        open(&log).unwrap().put(record("a", "1")).unwrap();
+        let content = fs::read_to_string(&log.0).unwrap();
+        fs::write(&log.0, format!("not json\n{}", content)).unwrap();
+        let e = open(&log).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}
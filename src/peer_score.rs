@@ -0,0 +1,217 @@
+// Misbehaviour scores of remote peers and the peers that are banned because of them.
+// Every misbehaviour lowers the score of a peer, negative scores recover over time. Peers whose
+// score drops to BAN_THRESHOLD are banned temporarily, peers can also be banned manually.
+// Changes of the ban list are queued, so that the swarm can refuse or allow connections.
+
+use libp2p::PeerId;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+const BAN_THRESHOLD: i32 = -100;
+
+// Duration of the bans that are caused by a low score.
+const TEMPORARY_BAN_DURATION: Duration = Duration::from_secs(10 * 60);
+
+// Points per minute by which a negative score recovers.
+const SCORE_RECOVERY_PER_MINUTE: i32 = 10;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Misbehaviour {
+    // the peer sent a message that could not be parsed or was rejected by a validator
+    MalformedMessage,
+    // the peer exceeded the rate limit of inbound requests
+    Spam,
+}
+
+impl Misbehaviour {
+    fn penalty(self) -> i32 {
+        match self {
+            Misbehaviour::MalformedMessage => 20,
+            Misbehaviour::Spam => 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Ban {
+    pub since: Instant,
+    // None if the peer is banned until it is unbanned manually
+    pub until: Option<Instant>,
+}
+
+#[derive(Debug, Clone)]
+pub enum BanChange {
+    Banned(PeerId),
+    Unbanned(PeerId),
+}
+
+#[derive(Debug)]
+struct PeerScore {
+    score: i32,
+    updated: Instant,
+}
+
+impl PeerScore {
+    fn new(now: Instant) -> Self {
+        PeerScore { score: 0, updated: now }
+    }
+
+    // Score including the recovery since the last update.
+    fn current(&self, now: Instant) -> i32 {
+        let minutes = (now - self.updated).as_secs() / 60;
+        let recovery = (minutes as i32).saturating_mul(SCORE_RECOVERY_PER_MINUTE);
+        self.score.saturating_add(recovery).min(0)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct BanList {
+    scores: HashMap<PeerId, PeerScore>,
+    bans: HashMap<PeerId, Ban>,
+    changes: VecDeque<BanChange>,
+}
+
+impl BanList {
+    // Lower the score of the peer and ban it if the score reaches the threshold.
+    // Returns the new score.
+    pub fn penalise(&mut self, peer: &PeerId, misbehaviour: Misbehaviour) -> i32 {
+        let now = Instant::now();
+        let score = self.scores.entry(peer.clone()).or_insert_with(|| PeerScore::new(now));
+        score.score = score.current(now) - misbehaviour.penalty();
+        score.updated = now;
+        let score = score.score;
+        if score <= BAN_THRESHOLD && !self.is_banned(peer) {
+            self.ban(peer.clone(), Some(TEMPORARY_BAN_DURATION));
+        }
+        score
+    }
+
+    pub fn score(&self, peer: &PeerId) -> i32 {
+        self.scores
+            .get(peer)
+            .map_or(0, |score| score.current(Instant::now()))
+    }
+
+    // Ban the peer for the duration, or until it is unbanned if no duration is given.
+    pub fn ban(&mut self, peer: PeerId, duration: Option<Duration>) {
+        let now = Instant::now();
+        let ban = Ban {
+            since: now,
+            until: duration.map(|duration| now + duration),
+        };
+        if self.bans.insert(peer.clone(), ban).is_none() {
+            self.changes.push_back(BanChange::Banned(peer));
+        }
+    }
+
+    // Lift the ban of the peer and reset its score, returns false if the peer was not banned.
+    pub fn unban(&mut self, peer: &PeerId) -> bool {
+        self.scores.remove(peer);
+        if self.bans.remove(peer).is_some() {
+            self.changes.push_back(BanChange::Unbanned(peer.clone()));
+            return true;
+        }
+        false
+    }
+
+    pub fn is_banned(&self, peer: &PeerId) -> bool {
+        self.bans.contains_key(peer)
+    }
+
+    pub fn bans(&self) -> impl Iterator<Item = (&PeerId, &Ban)> {
+        self.bans.iter()
+    }
+
+    // Lift all temporary bans that expired.
+    pub fn expire_bans(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<PeerId> = self
+            .bans
+            .iter()
+            .filter(|(_, ban)| ban.until.map_or(false, |until| until <= now))
+            .map(|(peer, _)| peer.clone())
+            .collect();
+        for peer in expired {
+            self.unban(&peer);
+        }
+    }
+
+    // Time at which the next temporary ban expires.
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.bans.values().filter_map(|ban| ban.until).min()
+    }
+
+    // Next change of the ban list, that was not applied to the swarm yet.
+    pub fn next_change(&mut self) -> Option<BanChange> {
+        self.changes.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn changes(ban_list: &mut BanList) -> Vec<String> {
+        std::iter::from_fn(|| ban_list.next_change())
+            .map(|change| match change {
+                BanChange::Banned(_) => "banned".to_string(),
+                BanChange::Unbanned(_) => "unbanned".to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bans_peer_when_score_reaches_threshold() {
+        let mut ban_list = BanList::default();
+        let peer = PeerId::random();
+        let penalty = Misbehaviour::MalformedMessage.penalty();
+        for i in 1..(-BAN_THRESHOLD / penalty) {
+            assert_eq!(ban_list.penalise(&peer, Misbehaviour::MalformedMessage), -i * penalty);
+            assert!(!ban_list.is_banned(&peer));
+        }
+        assert_eq!(ban_list.penalise(&peer, Misbehaviour::MalformedMessage), BAN_THRESHOLD);
+        assert!(ban_list.is_banned(&peer));
+        assert!(ban_list.next_expiry().is_some());
+        // further penalties do not ban the peer again
+        ban_list.penalise(&peer, Misbehaviour::MalformedMessage);
+        assert_eq!(changes(&mut ban_list), vec!["banned"]);
+    }
+
+    #[test]
+    fn negative_scores_recover_over_time() {
+        let now = Instant::now();
+        let mut score = PeerScore::new(now);
+        score.score = -50;
+        assert_eq!(score.current(now + Duration::from_secs(59)), -50);
+        assert_eq!(score.current(now + Duration::from_secs(3 * 60)), -50 + 3 * SCORE_RECOVERY_PER_MINUTE);
+        assert_eq!(score.current(now + Duration::from_secs(60 * 60)), 0);
+    }
+
+    #[test]
+    fn unban_resets_score() {
+        let mut ban_list = BanList::default();
+        let peer = PeerId::random();
+        ban_list.penalise(&peer, Misbehaviour::MalformedMessage);
+        ban_list.ban(peer.clone(), None);
+        assert!(ban_list.unban(&peer));
+        assert!(!ban_list.unban(&peer));
+        assert_eq!(ban_list.score(&peer), 0);
+        assert_eq!(changes(&mut ban_list), vec!["banned", "unbanned"]);
+    }
+
+    #[test]
+    fn only_temporary_bans_expire() {
+        let mut ban_list = BanList::default();
+        let (temporary, permanent) = (PeerId::random(), PeerId::random());
+        ban_list.ban(temporary.clone(), Some(Duration::from_secs(0)));
+        ban_list.ban(permanent.clone(), None);
+        assert!(ban_list.next_expiry().unwrap() <= Instant::now());
+        ban_list.expire_bans();
+        assert!(!ban_list.is_banned(&temporary));
+        assert!(ban_list.is_banned(&permanent));
+        assert_eq!(ban_list.next_expiry(), None);
+        assert_eq!(changes(&mut ban_list), vec!["banned", "banned", "unbanned"]);
+    }
+}
@@ -0,0 +1,48 @@
+// Liveness statistics of remote peers, that are collected from the results of the ping protocol.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+// Number of round-trip times that are kept per peer.
+const RTT_HISTORY_SIZE: usize = 10;
+
+#[derive(Debug, Default)]
+pub struct PeerStats {
+    rtts: VecDeque<Duration>,
+    // failures since the last successful ping
+    pub consecutive_failures: u32,
+    pub total_failures: u32,
+    pub last_success: Option<Instant>,
+}
+
+impl PeerStats {
+    pub fn record_success(&mut self, rtt: Duration) {
+        if self.rtts.len() == RTT_HISTORY_SIZE {
+            self.rtts.pop_front();
+        }
+        self.rtts.push_back(rtt);
+        self.consecutive_failures = 0;
+        self.last_success = Some(Instant::now());
+    }
+
+    // Returns the number of consecutive failures.
+    pub fn record_failure(&mut self) -> u32 {
+        self.consecutive_failures += 1;
+        self.total_failures += 1;
+        self.consecutive_failures
+    }
+
+    // Average of the recent round-trip times.
+    pub fn average_rtt(&self) -> Option<Duration> {
+        if self.rtts.is_empty() {
+            return None;
+        }
+        Some(self.rtts.iter().sum::<Duration>() / self.rtts.len() as u32)
+    }
+
+    pub fn last_rtt(&self) -> Option<Duration> {
+        self.rtts.back().copied()
+    }
+}
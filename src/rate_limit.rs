@@ -0,0 +1,185 @@
+// Token bucket rate limiting of inbound requests per peer and command type.
+// Every request takes a token from the bucket of its peer and command, buckets are refilled at a
+// constant rate up to their capacity. Requests that find an empty bucket are rejected with the
+// time after which the next token is available.
+
+use libp2p::PeerId;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+// Full buckets are dropped once there are more buckets than this.
+const MAX_BUCKETS: usize = 1024;
+
+// Slower refill rates are rejected, the time until the next token would be unreasonably long.
+pub const MIN_REFILL_RATE: f64 = 0.01;
+
+#[derive(Debug, Clone, Copy)]
+pub struct BucketConfig {
+    // maximal number of requests in a burst
+    pub capacity: u32,
+    // tokens that are added per second
+    pub refill_rate: f64,
+}
+
+impl BucketConfig {
+    // Check that the bucket admits requests at all and refills in reasonable time.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.capacity == 0 {
+            return Err("rate limit burst must be at least 1".to_string());
+        }
+        // also rejects NaN
+        if !(self.refill_rate >= MIN_REFILL_RATE) {
+            return Err(format!(
+                "rate limit must be at least {} requests per second, got {}",
+                MIN_REFILL_RATE, self.refill_rate
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    pub default: BucketConfig,
+    // limits of single command types, e.g. "FORWARD"
+    pub commands: HashMap<&'static str, BucketConfig>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        let mut commands = HashMap::new();
+        // forwarded requests cause outbound traffic to other peers
+        commands.insert(
+            "FORWARD",
+            BucketConfig {
+                capacity: 5,
+                refill_rate: 1.0,
+            },
+        );
+        RateLimitConfig {
+            default: BucketConfig {
+                capacity: 20,
+                refill_rate: 10.0,
+            },
+            commands,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, config: &BucketConfig, now: Instant) {
+        let elapsed = (now - self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * config.refill_rate).min(config.capacity as f64);
+        self.updated = now;
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: HashMap<(PeerId, &'static str), TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        RateLimiter {
+            config,
+            buckets: HashMap::new(),
+        }
+    }
+
+    // Take a token for the request, returns the duration after which the next request is allowed
+    // if the bucket is empty. The configured buckets are expected to be valid.
+    pub fn check(&mut self, peer: &PeerId, command: &'static str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let config = *self.config.commands.get(command).unwrap_or(&self.config.default);
+        if self.buckets.len() >= MAX_BUCKETS {
+            self.purge(now);
+        }
+        let bucket = self
+            .buckets
+            .entry((peer.clone(), command))
+            .or_insert_with(|| TokenBucket {
+                tokens: config.capacity as f64,
+                updated: now,
+            });
+        bucket.refill(&config, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - bucket.tokens) / config.refill_rate))
+    }
+
+    // Drop the buckets that are full, they behave like new buckets.
+    fn purge(&mut self, now: Instant) {
+        let config = &self.config;
+        self.buckets.retain(|(_, command), bucket| {
+            let config = config.commands.get(command).unwrap_or(&config.default);
+            bucket.refill(config, now);
+            bucket.tokens < config.capacity as f64
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: BucketConfig = BucketConfig {
+        capacity: 4,
+        refill_rate: 2.0,
+    };
+
+    #[test]
+    fn bucket_refills_at_rate_up_to_capacity() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket {
+            tokens: 0.0,
+            updated: start,
+        };
+        bucket.refill(&CONFIG, start + Duration::from_millis(500));
+        assert!((bucket.tokens - 1.0).abs() < 1e-9);
+        bucket.refill(&CONFIG, start + Duration::from_millis(1500));
+        assert!((bucket.tokens - 3.0).abs() < 1e-9);
+        bucket.refill(&CONFIG, start + Duration::from_secs(60));
+        assert!((bucket.tokens - 4.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn rejects_requests_above_burst() {
+        let mut config = RateLimitConfig::default();
+        config.default = CONFIG;
+        let mut limiter = RateLimiter::new(config);
+        let peer = PeerId::random();
+        for _ in 0..CONFIG.capacity {
+            assert_eq!(limiter.check(&peer, "GET"), Ok(()));
+        }
+        let retry_after = limiter.check(&peer, "GET").unwrap_err();
+        assert!(retry_after > Duration::from_millis(400) && retry_after <= Duration::from_millis(500));
+        // other peers and command types have their own buckets
+        assert_eq!(limiter.check(&PeerId::random(), "GET"), Ok(()));
+        assert_eq!(limiter.check(&peer, "PUT"), Ok(()));
+    }
+
+    #[test]
+    fn validate_rejects_unusable_buckets() {
+        assert!(CONFIG.validate().is_ok());
+        for refill_rate in &[0.0, -1.0, MIN_REFILL_RATE / 2.0, f64::NAN] {
+            let config = BucketConfig {
+                refill_rate: *refill_rate,
+                ..CONFIG
+            };
+            assert!(config.validate().is_err());
+        }
+        assert!(BucketConfig { capacity: 0, ..CONFIG }.validate().is_err());
+    }
+}
@@ -0,0 +1,148 @@
+// Policy for retrying outbound requests that failed, e.g. because the peer was not reachable.
+// The delay before a retry doubles with every attempt, only failures of the configured classes
+// are retried. Timeouts of requests that are not idempotent are not retried, because the peer may
+// already have executed them.
+
+use libp2p::request_response::OutboundFailure;
+use std::{str::FromStr, time::Duration};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FailureClass {
+    Dial,
+    Timeout,
+    ConnectionClosed,
+}
+
+impl FailureClass {
+    // Class of the failure, None for failures that are never retried.
+    pub fn of(error: &OutboundFailure) -> Option<Self> {
+        match error {
+            OutboundFailure::DialFailure => Some(FailureClass::Dial),
+            OutboundFailure::Timeout => Some(FailureClass::Timeout),
+            OutboundFailure::ConnectionClosed => Some(FailureClass::ConnectionClosed),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for FailureClass {
+    type Err = String;
+
+    // Parse the failure class name that is used on the command line.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dial" => Ok(FailureClass::Dial),
+            "timeout" => Ok(FailureClass::Timeout),
+            "closed" => Ok(FailureClass::ConnectionClosed),
+            _ => Err(format!("unknown failure class: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    // attempts including the first one
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub retry_on: Vec<FailureClass>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+            retry_on: vec![
+                FailureClass::Dial,
+                FailureClass::Timeout,
+                FailureClass::ConnectionClosed,
+            ],
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Delay before the next attempt after the given attempt failed, None if the request should not
+    // be retried. Requests that are not idempotent are only retried if the peer could not be dialed.
+    pub fn backoff(&self, attempt: u32, error: &OutboundFailure, idempotent: bool) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        let class = FailureClass::of(error)?;
+        if !self.retry_on.contains(&class) || (!idempotent && class != FailureClass::Dial) {
+            return None;
+        }
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        Some(
+            self.initial_backoff
+                .checked_mul(factor)
+                .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5),
+            ..RetryPolicy::default()
+        };
+        let backoffs: Vec<_> = (1..6)
+            .map(|attempt| policy.backoff(attempt, &OutboundFailure::DialFailure, true))
+            .collect();
+        assert_eq!(
+            backoffs,
+            vec![1, 2, 4, 5, 5]
+                .into_iter()
+                .map(|secs| Some(Duration::from_secs(secs)))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn backoff_is_capped_on_overflow() {
+        let policy = RetryPolicy {
+            max_attempts: u32::MAX,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(
+            policy.backoff(100, &OutboundFailure::DialFailure, true),
+            Some(policy.max_backoff)
+        );
+    }
+
+    #[test]
+    fn no_retry_after_max_attempts() {
+        let policy = RetryPolicy::default();
+        assert!(policy.backoff(2, &OutboundFailure::Timeout, true).is_some());
+        assert_eq!(policy.backoff(3, &OutboundFailure::Timeout, true), None);
+    }
+
+    #[test]
+    fn only_configured_classes_are_retried() {
+        let policy = RetryPolicy {
+            retry_on: vec![FailureClass::Dial],
+            ..RetryPolicy::default()
+        };
+        assert!(policy.backoff(1, &OutboundFailure::DialFailure, true).is_some());
+        assert_eq!(policy.backoff(1, &OutboundFailure::Timeout, true), None);
+        assert_eq!(policy.backoff(1, &OutboundFailure::ConnectionClosed, true), None);
+        assert_eq!(policy.backoff(1, &OutboundFailure::UnsupportedProtocols, true), None);
+    }
+
+    #[test]
+    fn non_idempotent_requests_are_only_retried_on_dial_failures() {
+        let policy = RetryPolicy::default();
+        assert!(policy.backoff(1, &OutboundFailure::DialFailure, false).is_some());
+        assert_eq!(policy.backoff(1, &OutboundFailure::Timeout, false), None);
+        assert_eq!(policy.backoff(1, &OutboundFailure::ConnectionClosed, false), None);
+    }
+}